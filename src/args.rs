@@ -1,6 +1,8 @@
 use std::path::PathBuf;
 
 use clap::{Parser, Subcommand};
+
+use crate::config::ApplyMode;
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 pub struct Args {
@@ -48,6 +50,62 @@ pub enum Command {
             required = false
         )]
         encrypt: bool,
+        #[arg(
+            short,
+            long,
+            help = "encrypt the file with the key of the given key group (implies --encrypt)"
+        )]
+        key_group: Option<String>,
+        #[arg(
+            long,
+            help = "store the file(s) in plaintext even if they look like they contain secrets",
+            required = false
+        )]
+        allow_secrets: bool,
+        #[arg(
+            long,
+            help = "store the encrypted file ASCII armored instead of binary",
+            required = false
+        )]
+        armor: bool,
+        #[arg(
+            long,
+            help = "encrypt the file with the given external backend from `[encryption.backends]` instead of age (implies --encrypt)"
+        )]
+        backend: Option<String>,
+        #[arg(
+            long,
+            help = "how the file is applied, overrides `mode` from the config (encrypted files are always copied)"
+        )]
+        mode: Option<ApplyMode>,
+        #[arg(
+            long,
+            help = "manage symlinks themselves instead of the files they point to",
+            required = false
+        )]
+        link: bool,
+        #[arg(
+            long,
+            help = "store the file as a template that is rendered for each machine on apply",
+            required = false
+        )]
+        template: bool,
+        #[arg(
+            long = "host",
+            value_name = "HOST",
+            help = "only manage the file(s) on the machine with this hostname, can be given multiple times"
+        )]
+        hosts: Vec<String>,
+        #[arg(
+            long,
+            help = "only manage the file(s) on this operating system, e.g. `linux` or `macos`"
+        )]
+        os: Option<String>,
+        #[arg(
+            long,
+            help = "only manage the file(s) on machines where this command is installed"
+        )]
+        requires_command: Option<String>,
     },
     #[command(about = "list all managed files")]
     List,
@@ -56,6 +114,53 @@ pub enum Command {
         #[arg(help = "relative or absolute path to file(s)")]
         files: Vec<PathBuf>,
     },
+    #[command(
+        about = "track all files matching a glob pattern, e.g. '~/.local/bin/*', now and in the future"
+    )]
+    Track {
+        #[arg(help = "glob pattern of files to track, quote it so the shell does not expand it")]
+        pattern: String,
+        #[arg(
+            short,
+            long,
+            help = "flags matching files as to be encrypted",
+            required = false
+        )]
+        encrypt: bool,
+        #[arg(
+            short,
+            long,
+            help = "encrypt matching files with the key of the given key group (implies --encrypt)"
+        )]
+        key_group: Option<String>,
+        #[arg(
+            short = 'x',
+            long = "exclude",
+            help = "pattern of files to leave out, relative to the pattern's directory (can be repeated)"
+        )]
+        excludes: Vec<String>,
+        #[arg(
+            long = "host",
+            value_name = "HOST",
+            help = "only manage matching files on the machine with this hostname, can be given multiple times"
+        )]
+        hosts: Vec<String>,
+        #[arg(
+            long,
+            help = "only manage matching files on this operating system, e.g. `linux` or `macos`"
+        )]
+        os: Option<String>,
+        #[arg(
+            long,
+            help = "only manage matching files on machines where this command is installed"
+        )]
+        requires_command: Option<String>,
+    },
+    #[command(about = "stop offering new files matching a glob pattern for management")]
+    Untrack {
+        #[arg(help = "glob pattern given to `conman track`")]
+        pattern: String,
+    },
     #[command(about = "apply managed configuration")]
     Apply {
         #[arg(help = "specific file(s) to apply")]
@@ -88,6 +193,98 @@ pub enum Command {
         )]
         no_confirm: bool,
     },
+    #[command(about = "encrypt an already managed file")]
+    Encrypt {
+        #[arg(help = "relative or absolute path to file(s)")]
+        files: Vec<PathBuf>,
+        #[arg(
+            short,
+            long,
+            help = "encrypt the file with the key of the given key group"
+        )]
+        key_group: Option<String>,
+        #[arg(
+            long,
+            help = "store the encrypted file ASCII armored instead of binary",
+            required = false
+        )]
+        armor: bool,
+        #[arg(
+            long,
+            help = "encrypt the file with the given external backend from `[encryption.backends]` instead of age"
+        )]
+        backend: Option<String>,
+    },
+    #[command(about = "store an already managed file unencrypted")]
+    Decrypt {
+        #[arg(help = "relative or absolute path to file(s)")]
+        files: Vec<PathBuf>,
+        #[arg(
+            long,
+            help = "skip asking for confirmation before decrypting each file",
+            required = false
+        )]
+        no_confirm: bool,
+    },
+    #[command(about = "re-encrypt all encrypted files with a new passphrase or new key(s)")]
+    Rekey {
+        #[arg(
+            long,
+            help = "prompt for a new passphrase to encrypt with",
+            required = false,
+            conflicts_with_all = ["recipients", "identity_file"]
+        )]
+        passphrase: bool,
+        #[arg(
+            long = "recipient",
+            help = "age or ssh public key to encrypt to, may be repeated",
+            required = false
+        )]
+        recipients: Vec<String>,
+        #[arg(long, help = "age identity file or ssh private key to decrypt with")]
+        identity_file: Option<PathBuf>,
+        #[arg(
+            short,
+            long,
+            help = "rekey the given key group instead of the default key"
+        )]
+        key_group: Option<String>,
+    },
+    #[command(
+        about = "mark the potential secrets in managed file(s) as safe to store in plaintext"
+    )]
+    AllowSecrets {
+        #[arg(help = "relative or absolute path to file(s)")]
+        files: Vec<PathBuf>,
+    },
+    #[command(about = "test-decrypt every encrypted file and report the ones that fail")]
+    VerifySecrets,
+    #[command(about = "manage single secret values stored in the encrypted vault")]
+    Secret {
+        #[command(subcommand)]
+        secret_op: SecretCommand,
+    },
+    #[command(
+        about = "run a command with the variables of an encrypted env file, e.g. `conman exec --env work -- terraform plan`"
+    )]
+    Exec {
+        #[arg(
+            long,
+            help = "name (with or without extension) or path of a managed encrypted KEY=VALUE file"
+        )]
+        env: String,
+        #[arg(last = true, required = true, help = "command to run")]
+        command: Vec<String>,
+    },
+    #[command(about = "preview how a managed template is rendered on this machine")]
+    Render {
+        #[arg(help = "relative or absolute path to the file")]
+        file: PathBuf,
+    },
+    #[command(
+        about = "move files stored under timestamped names to paths mirroring the system, e.g. `home/.config/nvim/init.lua`"
+    )]
+    MigrateLayout,
     #[command(about = "manage branches in conman")]
     Branch {
         #[command(subcommand)]
@@ -95,6 +292,22 @@ pub enum Command {
     },
 }
 
+#[derive(Subcommand, Debug, PartialEq, Eq)]
+pub enum SecretCommand {
+    #[command(about = "store a secret, referenced in plaintext files as `{{ secret.NAME }}`")]
+    Set {
+        name: String,
+        #[arg(help = "value of the secret, prompted for if omitted")]
+        value: Option<String>,
+    },
+    #[command(about = "print the value of a secret")]
+    Get { name: String },
+    #[command(about = "list the names of all secrets")]
+    List,
+    #[command(about = "remove a secret")]
+    Rm { name: String },
+}
+
 #[derive(Subcommand, Debug, PartialEq, Eq)]
 pub enum BranchCommand {
     #[command(about = "checkout a branch")]
@@ -0,0 +1,186 @@
+use std::{
+    fs::File,
+    io::{BufReader, Read},
+    path::Path,
+    process::{Child, ChildStdout, Command, Stdio},
+};
+
+use age::armor::{ArmoredReader, ArmoredWriter};
+use anyhow::{anyhow, Context, Result};
+use tracing::instrument;
+
+use crate::{
+    config::{EncryptionConfig, ExternalBackendConfig, KeyConfig},
+    file::{self, FileData, STREAM_BUFFER_SIZE},
+};
+
+/// Encrypts and decrypts the repo copies of encrypted files
+pub trait EncryptionBackend {
+    /// encrypt everything `plaintext` yields into `destination`
+    fn encrypt(&self, plaintext: &mut dyn Read, destination: &mut File) -> Result<()>;
+
+    /// open a reader over the plaintext of the encrypted file at `path`
+    fn decrypt(&self, path: &Path) -> Result<Box<dyn Read>>;
+}
+
+/// get the backend a `FileData` is encrypted with, `age` unless the file names another one
+pub fn for_file<'a>(
+    file_data: &FileData,
+    encryption: &'a EncryptionConfig,
+) -> Result<Box<dyn EncryptionBackend + 'a>> {
+    match file_data.backend.as_deref() {
+        Some(name) => Ok(Box::new(ExternalBackend::new(name, encryption)?)),
+        None => Ok(Box::new(AgeBackend {
+            key: encryption.key(file_data.key_group.as_deref())?,
+            armor: file_data.armored(encryption),
+        })),
+    }
+}
+
+/// The default backend, encrypting with `age` using the key of the file's key group
+pub struct AgeBackend<'a> {
+    pub key: &'a KeyConfig,
+    pub armor: bool,
+}
+
+impl EncryptionBackend for AgeBackend<'_> {
+    #[instrument(skip_all)]
+    fn encrypt(&self, plaintext: &mut dyn Read, destination: &mut File) -> Result<()> {
+        let encryptor = file::init_encryptor(self.key)?;
+
+        let armored_writer =
+            ArmoredWriter::wrap_output(destination, file::armor_format(self.armor))?;
+        let mut writer = encryptor.wrap_output(armored_writer)?;
+        file::stream(plaintext, &mut writer)?;
+        writer.finish()?.finish()?;
+
+        tracing::trace!("encrypted with age");
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    fn decrypt(&self, path: &Path) -> Result<Box<dyn Read>> {
+        // both ASCII armored and binary files are read, independent of the current armor setting
+        let encrypted_file = ArmoredReader::new(BufReader::with_capacity(
+            STREAM_BUFFER_SIZE,
+            File::open(path)?,
+        ));
+
+        let decryptor = age::Decryptor::new_buffered(encrypted_file)?;
+
+        let identities = file::init_identities(self.key, decryptor.is_scrypt())?;
+
+        let reader = decryptor.decrypt(identities.iter().map(|identity| identity.as_ref()))?;
+
+        Ok(Box::new(reader))
+    }
+}
+
+/// A backend that pipes file contents through user configured commands, e.g. `sops` or `gpg`
+///
+/// the commands are run with `sh -c`, reading their input from stdin and writing their output to
+/// stdout
+pub struct ExternalBackend<'a> {
+    name: &'a str,
+    config: &'a ExternalBackendConfig,
+}
+
+impl<'a> ExternalBackend<'a> {
+    pub fn new(name: &str, encryption: &'a EncryptionConfig) -> Result<Self> {
+        let Some((name, config)) = encryption.backends.get_key_value(name) else {
+            return Err(anyhow!(
+                "encryption backend '{name}' is not configured on this machine"
+            ));
+        };
+
+        Ok(Self { name, config })
+    }
+
+    fn command(&self, command: &str) -> Command {
+        let mut process = Command::new("sh");
+        process.arg("-c").arg(command);
+        process
+    }
+}
+
+impl EncryptionBackend for ExternalBackend<'_> {
+    #[instrument(skip_all, fields(backend = self.name))]
+    fn encrypt(&self, plaintext: &mut dyn Read, destination: &mut File) -> Result<()> {
+        let mut child = self
+            .command(&self.config.encrypt_command)
+            .stdin(Stdio::piped())
+            .stdout(destination.try_clone()?)
+            .spawn()
+            .with_context(|| format!("failed to run the encrypt command of '{}'", self.name))?;
+
+        // the output goes straight to the file, so writing all input first can not deadlock
+        let mut stdin = child.stdin.take().unwrap();
+        let streamed = file::stream(plaintext, &mut stdin);
+        drop(stdin);
+
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(anyhow!(
+                "the encrypt command of '{}' failed with {status}",
+                self.name
+            ));
+        }
+        streamed?;
+
+        tracing::trace!("encrypted with external command");
+        Ok(())
+    }
+
+    #[instrument(skip(self), fields(backend = self.name))]
+    fn decrypt(&self, path: &Path) -> Result<Box<dyn Read>> {
+        let mut child = self
+            .command(&self.config.decrypt_command)
+            .stdin(File::open(path)?)
+            .stdout(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("failed to run the decrypt command of '{}'", self.name))?;
+
+        let stdout = child.stdout.take().unwrap();
+
+        Ok(Box::new(CommandReader {
+            name: self.name.to_string(),
+            child,
+            stdout,
+        }))
+    }
+}
+
+/// Reads the output of a decrypt command, failing at the end if the command did not succeed
+struct CommandReader {
+    name: String,
+    child: Child,
+    stdout: ChildStdout,
+}
+
+impl Read for CommandReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.stdout.read(buf)?;
+
+        if read == 0 && !buf.is_empty() {
+            let status = self.child.wait()?;
+            if !status.success() {
+                return Err(std::io::Error::other(format!(
+                    "the decrypt command of '{}' failed with {status}",
+                    self.name
+                )));
+            }
+        }
+
+        Ok(read)
+    }
+}
+
+impl Drop for CommandReader {
+    fn drop(&mut self) {
+        // a reader dropped early must not leave the command running
+        if let Ok(None) = self.child.try_wait() {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+}
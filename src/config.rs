@@ -1,22 +1,262 @@
-use std::{fmt::Debug, fs::File, io::Read, path::PathBuf};
+use std::{
+    collections::BTreeMap,
+    fmt::Debug,
+    fs::File,
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{Arc, Mutex},
+};
 
-use crate::paths::APPLICATION_NAME;
-use anyhow::Result;
-use directories::BaseDirs;
+use age::secrecy::{ExposeSecret, SecretBox, SecretString};
+use anyhow::{anyhow, Result};
+use dialoguer::{theme::ColorfulTheme, Password};
 use serde::{Deserialize, Deserializer, Serialize};
 use tracing::instrument;
 
-const CONFIG_FILE_NAME: &str = "config.toml";
+use crate::file::{self, StagedFile};
+
+pub(crate) const PASSPHRASE_ENV_VAR: &str = "CONMAN_PASSPHRASE";
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Config {
+    /// how managed files are applied unless they set their own mode
+    #[serde(default, skip_serializing_if = "ApplyMode::is_copy")]
+    pub mode: ApplyMode,
     pub encryption: EncryptionConfig,
     pub upstream: UpstreamConfig,
+    /// machine specific values templates can refer to as `data.NAME`, e.g. `[data] email = "..."`
+    #[serde(default, skip_serializing_if = "toml::Table::is_empty")]
+    pub data: toml::Table,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+/// How `apply` puts a managed file in place
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ApplyMode {
+    /// copy the repo copy to the system path, edits have to be collected
+    #[default]
+    Copy,
+    /// make the system path a symlink to the repo copy, so edits are live in the repo.
+    /// Encrypted files and files with secret placeholders are always copied
+    Symlink,
+}
+
+impl ApplyMode {
+    fn is_copy(&self) -> bool {
+        *self == ApplyMode::Copy
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct EncryptionConfig {
-    pub passphrase: String, // we will use this together with `age` for file encryption
+    /// the key used for encrypted files that are not part of a key group
+    #[serde(flatten)]
+    pub default_key: KeyConfig,
+    /// named keys, e.g. `[encryption.key_groups.work]`, so that files can be restricted to the
+    /// machines that have the key of their group
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub key_groups: BTreeMap<String, KeyConfig>,
+    /// store the metadata entries of newly encrypted files encrypted as well and give their repo
+    /// copies opaque names, so that the repo does not reveal which files are kept secret
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub privacy: bool,
+    /// write encrypted repo copies as ASCII armored (PEM-like) text instead of binary, so that
+    /// diffs and stats stay readable. Files can override this individually
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub armor: bool,
+    /// external encryption backends, e.g. `[encryption.backends.sops]`, for files that have to
+    /// stay compatible with other tools
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub backends: BTreeMap<String, ExternalBackendConfig>,
+}
+
+/// Commands an external encryption backend pipes file contents through, run with `sh -c`
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ExternalBackendConfig {
+    /// reads the plaintext from stdin and writes the encrypted contents to stdout
+    pub encrypt_command: String,
+    /// reads the encrypted contents from stdin and writes the plaintext to stdout
+    pub decrypt_command: String,
+}
+
+impl EncryptionConfig {
+    /// get the key of the given key group, or the default key if no key group is given
+    pub fn key(&self, key_group: Option<&str>) -> Result<&KeyConfig> {
+        let Some(key_group) = key_group else {
+            return Ok(&self.default_key);
+        };
+
+        self.key_groups
+            .get(key_group)
+            .ok_or_else(|| anyhow!("key group '{key_group}' is not configured on this machine"))
+    }
+
+    /// the default key and the key of every key group, along with the name of its group
+    pub fn keys(&self) -> impl Iterator<Item = (Option<&str>, &KeyConfig)> {
+        std::iter::once((None, &self.default_key)).chain(
+            self.key_groups
+                .iter()
+                .map(|(name, key)| (Some(name.as_str()), key)),
+        )
+    }
+
+    /// check whether the key of the given key group can be used on this machine
+    pub fn key_is_available(&self, key_group: Option<&str>) -> bool {
+        self.key(key_group)
+            .map(|key| key.is_available())
+            .unwrap_or(false)
+    }
+
+    /// let each key group know its own name, used for prompts and environment variables
+    fn name_key_groups(&mut self) {
+        for (name, key) in self.key_groups.iter_mut() {
+            key.group = Some(name.clone());
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct KeyConfig {
+    /// plaintext passphrase used together with `age`'s scrypt recipient.
+    /// Prefer `passphrase_command`, `$CONMAN_PASSPHRASE` or the interactive prompt
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub passphrase: Option<String>,
+    /// command whose first line of output is used as the passphrase, e.g. `pass show conman`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub passphrase_command: Option<String>,
+    /// `age` identity file used to decrypt files encrypted to `recipients`
+    #[serde(
+        default,
+        deserialize_with = "path_resolver",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub identity_file: Option<PathBuf>,
+    /// `age` public keys new files are encrypted to
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub recipients: Vec<String>,
+    /// name of the key group this key belongs to, `None` for the default key
+    #[serde(skip)]
+    pub(crate) group: Option<String>,
+    /// the passphrase once it has been resolved, shared between clones of the config so that
+    /// the user is prompted at most once per run
+    #[serde(skip)]
+    pub(crate) resolved_passphrase: Arc<Mutex<Option<SecretString>>>,
+    /// the secret plaintext hashes are keyed with, shared between clones of the config like the
+    /// passphrase, see `file::hash_secret`
+    #[serde(skip)]
+    pub(crate) hash_key: Arc<Mutex<HashKey>>,
+}
+
+/// The random secret the plaintext hashes and opaque names of a key are keyed with
+///
+/// it is stored in the metadata encrypted with the key rather than derived from it, so that
+/// neither rewriting the identity file nor rotating the key changes the hashes
+#[derive(Debug, Default)]
+pub(crate) struct HashKey {
+    /// the ASCII armored `age` encrypted secret, `None` until it is read from or stored in the
+    /// metadata
+    pub(crate) sealed: Option<String>,
+    /// the decrypted secret, once it has been used
+    pub(crate) secret: Option<SecretBox<[u8; 32]>>,
+}
+
+impl KeyConfig {
+    /// a key is unavailable if it relies on an identity file that does not exist on this machine
+    pub fn is_available(&self) -> bool {
+        self.identity_file
+            .as_ref()
+            .map(|identity_file| identity_file.exists())
+            .unwrap_or(true)
+    }
+
+    /// the environment variable the passphrase of this key can be read from, e.g.
+    /// `CONMAN_PASSPHRASE_WORK` for the key group `work`
+    pub fn passphrase_env_var(&self) -> String {
+        match self.group.as_ref() {
+            Some(group) => format!(
+                "{PASSPHRASE_ENV_VAR}_{}",
+                group.to_uppercase().replace('-', "_")
+            ),
+            None => PASSPHRASE_ENV_VAR.to_string(),
+        }
+    }
+
+    /// replace the passphrase used from now on
+    ///
+    /// the plaintext `passphrase` is only updated if one was configured to begin with, other
+    /// passphrase sources have to be updated by the user
+    pub fn set_passphrase(&mut self, passphrase: SecretString) {
+        if self.passphrase.is_some() {
+            self.passphrase = Some(passphrase.expose_secret().to_string());
+        }
+        self.resolved_passphrase = Arc::new(Mutex::new(Some(passphrase)));
+    }
+
+    /// resolve the passphrase from, in order, `$CONMAN_PASSPHRASE`, `passphrase_command`, the
+    /// plaintext `passphrase` or an interactive prompt
+    #[instrument(skip(self))]
+    pub fn passphrase(&self) -> Result<SecretString> {
+        let mut resolved_passphrase = self.resolved_passphrase.lock().unwrap();
+
+        if let Some(passphrase) = resolved_passphrase.as_ref() {
+            return Ok(passphrase.expose_secret().into());
+        }
+
+        let passphrase = self.resolve_passphrase()?;
+        *resolved_passphrase = Some(passphrase.expose_secret().into());
+
+        Ok(passphrase)
+    }
+
+    fn resolve_passphrase(&self) -> Result<SecretString> {
+        let env_var = self.passphrase_env_var();
+
+        if let Ok(passphrase) = std::env::var(&env_var) {
+            tracing::trace!("using passphrase from ${env_var}");
+            return Ok(passphrase.into());
+        }
+
+        if let Some(command) = self.passphrase_command.as_ref() {
+            tracing::trace!(command = command, "running passphrase command");
+            let output = Command::new("sh").arg("-c").arg(command).output()?;
+
+            if !output.status.success() {
+                return Err(anyhow!(
+                    "passphrase command '{command}' failed: {}",
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ));
+            }
+
+            let stdout = String::from_utf8(output.stdout)?;
+            let passphrase = stdout.lines().next().unwrap_or_default().to_string();
+
+            if passphrase.is_empty() {
+                return Err(anyhow!("passphrase command '{command}' printed nothing"));
+            }
+
+            return Ok(passphrase.into());
+        }
+
+        if let Some(passphrase) = self.passphrase.as_ref() {
+            tracing::warn!(
+                "using the plaintext passphrase from config.toml, consider `passphrase_command` or ${env_var} instead"
+            );
+            return Ok(passphrase.clone().into());
+        }
+
+        let prompt = match self.group.as_ref() {
+            Some(group) => format!("Encryption passphrase for key group '{group}'"),
+            None => "Encryption passphrase".to_string(),
+        };
+
+        tracing::trace!("no passphrase configured, prompting");
+        let passphrase = Password::with_theme(&ColorfulTheme::default())
+            .with_prompt(prompt)
+            .interact()?;
+
+        Ok(passphrase.into())
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -31,14 +271,14 @@ pub struct UpstreamConfig {
 impl Default for Config {
     fn default() -> Self {
         Self {
-            encryption: EncryptionConfig {
-                passphrase: String::new(),
-            },
+            mode: ApplyMode::default(),
+            encryption: EncryptionConfig::default(),
             upstream: UpstreamConfig {
                 url: String::new(),
                 key_file: None,
                 branch: String::new(),
             },
+            data: toml::Table::new(),
         }
     }
 }
@@ -50,44 +290,49 @@ pub fn default_branch() -> String {
 
 impl Config {
     #[instrument]
-    pub fn read() -> Result<Self> {
-        let config_file = Self::config_file();
-
-        let mut config_file = File::open(&config_file)?;
+    pub fn read(config_file: &Path) -> Result<Self> {
+        let mut config_file = File::open(config_file)?;
 
         let mut contents = String::new();
         config_file.read_to_string(&mut contents)?;
 
-        let config: Config = toml::de::from_str(&contents)?;
+        let mut config: Config = toml::de::from_str(&contents)?;
+        config.encryption.name_key_groups();
 
         tracing::trace!("read config");
         Ok(config)
     }
 
+    /// write the config atomically, so a failed write never leaves a truncated config behind
     #[instrument(skip(self))]
-    pub fn write(&self) -> Result<()> {
-        let config_file = Self::config_file();
+    pub fn write(&self, config_file: &Path) -> Result<()> {
+        self.stage(config_file)?.commit()?;
+        tracing::trace!("wrote config to {}", config_file.display());
 
+        Ok(())
+    }
+
+    /// write the config to a temporary file that replaces `config_file` once committed, see
+    /// `file::stage_file`
+    #[instrument(skip(self))]
+    pub fn stage(&self, config_file: &Path) -> Result<StagedFile> {
         let toml = toml::to_string(&self)?;
         tracing::trace!("serialized config");
 
-        std::fs::write(&config_file, toml)?;
-        tracing::trace!("wrote config to {}", config_file.display());
-
-        Ok(())
+        file::stage_file(config_file, None, |file| {
+            Ok(file.write_all(toml.as_bytes())?)
+        })
     }
 
     #[instrument]
-    pub fn write_default_config() -> Result<()> {
-        let config_file = Self::config_file();
-
+    pub fn write_default_config(config_file: &Path) -> Result<()> {
         println!("Config file not found, creating default...");
         let default_config = Self::default();
 
         let toml = toml::to_string(&default_config)?;
         tracing::trace!("serialized default config");
 
-        std::fs::write(&config_file, toml)?;
+        std::fs::write(config_file, toml)?;
 
         println!(
             "Wrote empty config to '{}'. Please populate it!",
@@ -96,14 +341,6 @@ impl Config {
 
         Ok(())
     }
-
-    fn config_file() -> PathBuf {
-        let base_dirs = BaseDirs::new().unwrap();
-        base_dirs
-            .config_dir()
-            .join(APPLICATION_NAME)
-            .join(CONFIG_FILE_NAME)
-    }
 }
 
 #[instrument(skip(de))]
@@ -113,7 +350,7 @@ where
 {
     let maybe_path: Option<PathBuf> = Option::deserialize(de)?;
     let Some(unresolved_path) = maybe_path else {
-        tracing::trace!("no ssh key file path specified");
+        tracing::trace!("no key file path specified");
         return Ok(None);
     };
 
@@ -128,8 +365,10 @@ where
     let expanded_path = shellexpand::tilde(unresolved_path_as_str);
     tracing::trace!(expanded_path = ?expanded_path, "expanded key file path");
 
-    let resolved_path = std::fs::canonicalize(expanded_path.into_owned()).unwrap();
-    tracing::trace!(path=?resolved_path, "resolved ssh key file path");
+    // NOTE: keys may legitimately be missing on some machines, e.g. the key of a key group
+    let expanded_path = PathBuf::from(expanded_path.into_owned());
+    let resolved_path = std::fs::canonicalize(&expanded_path).unwrap_or(expanded_path);
+    tracing::trace!(path=?resolved_path, "resolved key file path");
 
     Ok(Some(resolved_path))
 }
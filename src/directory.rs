@@ -0,0 +1,187 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
+use tracing::instrument;
+use walkdir::WalkDir;
+
+/// name of the file listing the glob patterns of files to leave out of a tracked directory
+const IGNORE_FILE_NAME: &str = ".conmanignore";
+
+/// nested git repositories can not be stored in the conman repo
+const ALWAYS_IGNORED: [&str; 1] = [".git"];
+
+/// Glob patterns of files to leave out of a tracked directory, read from its `.conmanignore`
+///
+/// one pattern per line, blank lines and lines starting with `#` are skipped. Like in a
+/// `.gitignore`, patterns without a `/` match the name of any file or directory, e.g. `*.log`,
+/// while patterns with a `/` match the path relative to the tracked directory, e.g. `lazy/cache`.
+/// Ignoring a directory ignores everything inside it
+#[derive(Debug)]
+pub struct IgnoreRules {
+    names: GlobSet,
+    paths: GlobSet,
+}
+
+impl IgnoreRules {
+    /// read the ignore rules of the directory at `root`, which has none if it has no ignore file
+    #[instrument]
+    pub fn read(root: &Path) -> Result<Self> {
+        let ignore_file = root.join(IGNORE_FILE_NAME);
+
+        let patterns = match std::fs::read_to_string(&ignore_file) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        Self::parse(&patterns).with_context(|| format!("invalid '{}'", ignore_file.display()))
+    }
+
+    pub fn parse(patterns: &str) -> Result<Self> {
+        let mut names = GlobSetBuilder::new();
+        let mut paths = GlobSetBuilder::new();
+
+        for name in ALWAYS_IGNORED {
+            names.add(Glob::new(name)?);
+        }
+
+        for line in patterns.lines() {
+            let pattern = line.trim();
+            if pattern.is_empty() || pattern.starts_with('#') {
+                continue;
+            }
+
+            let pattern = pattern.trim_end_matches('/');
+
+            match pattern.strip_prefix('/') {
+                Some(pattern) => paths.add(Glob::new(pattern)?),
+                None if pattern.contains('/') => paths.add(Glob::new(pattern)?),
+                None => names.add(Glob::new(pattern)?),
+            };
+        }
+
+        Ok(Self {
+            names: names.build()?,
+            paths: paths.build()?,
+        })
+    }
+
+    /// whether the file or directory at `relative_path` within the tracked directory is ignored
+    pub fn is_ignored(&self, relative_path: &Path) -> bool {
+        relative_path
+            .iter()
+            .any(|component| self.names.is_match(Path::new(component)))
+            || relative_path
+                .ancestors()
+                .filter(|ancestor| !ancestor.as_os_str().is_empty())
+                .any(|ancestor| self.paths.is_match(ancestor))
+    }
+}
+
+/// list all files in the directory at `root` that are not ignored, in a stable order
+#[instrument]
+pub fn walk(root: &Path) -> Result<Vec<PathBuf>> {
+    let rules = IgnoreRules::read(root)?;
+
+    let mut files = vec![];
+
+    let entries = WalkDir::new(root)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|entry| {
+            entry
+                .path()
+                .strip_prefix(root)
+                .map(|relative_path| !rules.is_ignored(relative_path))
+                .unwrap_or(true)
+        });
+
+    for entry in entries {
+        let entry = entry?;
+
+        if entry.file_type().is_file() {
+            files.push(entry.into_path());
+        }
+    }
+
+    tracing::trace!(count = files.len(), "found files in directory");
+    Ok(files)
+}
+
+/// list all files matching the glob `pattern`, e.g. `/home/user/.config/fish/**/*.fish`, leaving
+/// out those matching any of the `excludes`, which follow the syntax of a `.conmanignore` and are
+/// relative to the non-glob prefix of `pattern`
+///
+/// like in a shell, `*` does not match across directories, use `**` for that
+#[instrument]
+pub fn glob(pattern: &Path, excludes: &[String]) -> Result<Vec<PathBuf>> {
+    let matcher = GlobBuilder::new(&pattern.to_string_lossy())
+        .literal_separator(true)
+        .build()
+        .with_context(|| format!("invalid pattern '{}'", pattern.display()))?
+        .compile_matcher();
+    let rules = IgnoreRules::parse(&excludes.join("\n"))?;
+
+    // a pattern without globs names a single file, which may not exist yet
+    if is_literal(pattern) {
+        return Ok(pattern
+            .is_file()
+            .then(|| pattern.to_path_buf())
+            .into_iter()
+            .collect());
+    }
+
+    // only the directory before the first component with a glob in it has to be walked
+    let root: PathBuf = pattern
+        .components()
+        .take_while(|component| !has_glob(component.as_os_str().to_string_lossy().as_ref()))
+        .collect();
+
+    if !root.is_dir() {
+        return Ok(vec![]);
+    }
+
+    let mut walker = WalkDir::new(&root).sort_by_file_name();
+    if !has_recursive_glob(pattern) {
+        walker = walker.max_depth(pattern.components().count() - root.components().count());
+    }
+
+    let mut files = vec![];
+
+    let entries = walker.into_iter().filter_entry(|entry| {
+        entry
+            .path()
+            .strip_prefix(&root)
+            .map(|relative_path| !rules.is_ignored(relative_path))
+            .unwrap_or(true)
+    });
+
+    for entry in entries {
+        let entry = entry?;
+
+        if entry.file_type().is_file() && matcher.is_match(entry.path()) {
+            files.push(entry.into_path());
+        }
+    }
+
+    tracing::trace!(count = files.len(), "found files matching pattern");
+    Ok(files)
+}
+
+/// whether `pattern` contains no globs and matches nothing but itself
+pub fn is_literal(pattern: &Path) -> bool {
+    !pattern
+        .components()
+        .any(|component| has_glob(component.as_os_str().to_string_lossy().as_ref()))
+}
+
+fn has_glob(component: &str) -> bool {
+    component.contains(['*', '?', '[', '{'])
+}
+
+fn has_recursive_glob(pattern: &Path) -> bool {
+    pattern
+        .components()
+        .any(|component| component.as_os_str().to_string_lossy().contains("**"))
+}
@@ -1,13 +1,32 @@
 use std::{
+    collections::BTreeSet,
+    ffi::OsString,
     fs::File,
-    io::{Read, Write},
-    path::PathBuf,
+    io::{BufRead, BufReader, ErrorKind, Read, Write},
+    path::{Path, PathBuf},
 };
 
-use age::{secrecy::SecretString, Decryptor, Encryptor};
-use anyhow::Result;
+use age::{
+    armor::{ArmoredReader, ArmoredWriter, Format},
+    secrecy::{ExposeSecret, SecretBox, SecretString},
+    Callbacks, Decryptor, Encryptor, Identity, IdentityFile, Recipient,
+};
+use anyhow::{anyhow, Context, Result};
+use dialoguer::{theme::ColorfulTheme, Confirm, Input, Password};
+use hmac::{Hmac, Mac};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::Sha256;
 use tracing::instrument;
+use zeroize::Zeroizing;
+
+use crate::{
+    backend::{self, EncryptionBackend},
+    config::{ApplyMode, Config, EncryptionConfig, KeyConfig},
+    directory,
+    machine::Conditions,
+    template,
+    vault::{self, Vault},
+};
 
 #[derive(Debug)]
 pub enum CacheVerdict {
@@ -16,7 +35,7 @@ pub enum CacheVerdict {
     DoNothing,
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 pub struct FileData {
     #[serde(
         deserialize_with = "deserialize_metadata_path",
@@ -29,29 +48,414 @@ pub struct FileData {
     )]
     pub repo_path: PathBuf,
     pub encrypted: bool,
+    /// the key group whose key is used to encrypt this file, the default key if `None`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_group: Option<String>,
+    /// keyed hash of the plaintext of an encrypted file, used to detect changes without
+    /// decrypting the repo copy or comparing it to the ciphertext
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub plaintext_hash: Option<String>,
+    /// whether the entry is stored encrypted in the metadata, see `EncryptionConfig::privacy`
+    #[serde(skip)]
+    pub private: bool,
+    /// fingerprints of potential secrets that were confirmed to be safe to store in plaintext
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_secrets: Vec<String>,
+    /// store the encrypted repo copy ASCII armored, overrides `EncryptionConfig::armor`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub armor: Option<bool>,
+    /// name of the external encryption backend the file is encrypted with, `age` if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backend: Option<String>,
+    /// unix mode bits of the system file, e.g. `0o755` for an executable script, recorded on
+    /// add and collect and restored on apply. Stored as an octal string
+    #[serde(
+        default,
+        deserialize_with = "deserialize_permissions",
+        serialize_with = "serialize_permissions",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub permissions: Option<u32>,
+    /// how the file is applied, overrides `Config::mode`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<ApplyMode>,
+    /// target of a `system_path` that is managed as a symlink itself, rather than the file it
+    /// points to. Such entries have no repo copy, `apply` recreates the link
+    #[serde(
+        default,
+        deserialize_with = "deserialize_optional_metadata_path",
+        serialize_with = "serialize_optional_metadata_path",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub link_target: Option<PathBuf>,
+    /// whether the repo copy is a template that is rendered for each machine on apply, see
+    /// `template::render`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub template: bool,
+    /// the machines the file is managed on, see `applies_to_this_machine`
+    #[serde(flatten)]
+    pub conditions: Conditions,
+}
+
+/// A directory that is tracked as a whole
+///
+/// the files inside it are managed as regular `FileData` entries, which `collect` adds and removes
+/// as files appear in or disappear from the directory. Files matching its `.conmanignore` are left
+/// out, see `IgnoreRules`
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct DirectoryData {
+    #[serde(
+        deserialize_with = "deserialize_metadata_path",
+        serialize_with = "serialize_metadata_path"
+    )]
+    pub system_path: PathBuf,
+    /// the repo directory the files are stored in, mirroring the layout of `system_path`
+    #[serde(
+        deserialize_with = "deserialize_metadata_path",
+        serialize_with = "serialize_metadata_path"
+    )]
+    pub repo_path: PathBuf,
+    /// whether files inside the directory are encrypted
+    pub encrypted: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_group: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backend: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub armor: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<ApplyMode>,
+    /// the machines files inside the directory are managed on
+    #[serde(flatten)]
+    pub conditions: Conditions,
+    /// whether the entry is stored encrypted in the metadata like the files inside it, see
+    /// `EncryptionConfig::privacy`
+    #[serde(skip)]
+    pub private: bool,
+}
+
+impl DirectoryData {
+    /// create the `FileData` of a file inside the directory, inheriting its encryption settings
+    /// and privacy
+    pub fn file_data(&self, system_path: PathBuf) -> Result<FileData> {
+        let relative_path = system_path.strip_prefix(&self.system_path)?;
+        let repo_path = self.repo_path.join(relative_path);
+
+        let mut file_data = FileData::new(
+            system_path,
+            repo_path,
+            self.encrypted,
+            self.key_group.clone(),
+        );
+        file_data.backend = self.backend.clone();
+        file_data.armor = self.armor;
+        file_data.mode = self.mode;
+        file_data.conditions = self.conditions.clone();
+        // the repo path is replaced with an opaque one before the file is added, see
+        // `Paths::private_repo_file_path`
+        file_data.private = self.private;
+
+        Ok(file_data)
+    }
+
+    /// whether the file at `system_path` is inside the directory
+    pub fn contains(&self, system_path: &Path) -> bool {
+        system_path.starts_with(&self.system_path)
+    }
+
+    /// whether the key the files inside the directory are encrypted with is available
+    pub fn key_is_available(&self, encryption: &EncryptionConfig) -> bool {
+        if !self.encrypted {
+            return true;
+        }
+
+        match self.backend.as_deref() {
+            Some(backend) => encryption.backends.contains_key(backend),
+            None => encryption.key_is_available(self.key_group.as_deref()),
+        }
+    }
+}
+
+/// A glob pattern, e.g. `~/.local/bin/*`, whose matching files are offered for management
+///
+/// rules are re-evaluated on every `collect` and `status`, files they match are managed as regular
+/// `FileData` entries once collected
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct TrackingRule {
+    #[serde(
+        deserialize_with = "deserialize_metadata_path",
+        serialize_with = "serialize_metadata_path"
+    )]
+    pub pattern: PathBuf,
+    /// whether matching files are encrypted
+    #[serde(default)]
+    pub encrypt: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_group: Option<String>,
+    /// patterns of files to leave out, with the same syntax as a `.conmanignore`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub excludes: Vec<String>,
+    /// the machines matching files are managed on, rules for other machines match nothing
+    #[serde(flatten)]
+    pub conditions: Conditions,
+    /// whether the rule is stored encrypted in the metadata like the files it matches, see
+    /// `EncryptionConfig::privacy`
+    #[serde(skip)]
+    pub private: bool,
+}
+
+impl TrackingRule {
+    /// turn a pattern given on the command line into an absolute one, expanding a leading `~`
+    pub fn normalize_pattern(pattern: &str) -> Result<PathBuf> {
+        let pattern = PathBuf::from(shellexpand::tilde(pattern).as_ref());
+
+        if pattern.is_absolute() {
+            return Ok(pattern);
+        }
+
+        Ok(std::env::current_dir()?.join(pattern))
+    }
+
+    /// all files currently matching the rule, managed or not
+    pub fn matching_files(&self) -> Result<Vec<PathBuf>> {
+        directory::glob(&self.pattern, &self.excludes)
+    }
 }
 
 impl FileData {
-    pub fn new(system_path: PathBuf, repo_path: PathBuf, encrypted: bool) -> Self {
+    pub fn new(
+        system_path: PathBuf,
+        repo_path: PathBuf,
+        encrypted: bool,
+        key_group: Option<String>,
+    ) -> Self {
         Self {
             system_path,
             repo_path,
             encrypted,
+            key_group,
+            plaintext_hash: None,
+            private: false,
+            allowed_secrets: vec![],
+            armor: None,
+            backend: None,
+            permissions: None,
+            mode: None,
+            link_target: None,
+            template: false,
+            conditions: Conditions::default(),
+        }
+    }
+
+    /// whether all conditions of the file are met on this machine, files that do not apply are
+    /// skipped by `apply`, `collect` and `status` as if they were not managed
+    pub fn applies_to_this_machine(&self) -> bool {
+        self.conditions.apply_to_this_machine()
+    }
+
+    /// create the `FileData` of a symlink that is managed itself, recording its current target
+    pub fn link(system_path: PathBuf, repo_path: PathBuf) -> Result<Self> {
+        let link_target = std::fs::read_link(&system_path)
+            .with_context(|| format!("'{}' is not a symlink", system_path.display()))?;
+
+        let mut file_data = Self::new(system_path, repo_path, false, None);
+        file_data.link_target = Some(link_target);
+
+        Ok(file_data)
+    }
+
+    /// whether the entry is a symlink managed itself, see `link_target`
+    pub fn is_link(&self) -> bool {
+        self.link_target.is_some()
+    }
+
+    /// the current target of a managed symlink if it differs from the recorded `link_target`
+    pub fn changed_link_target(&self) -> Option<PathBuf> {
+        let current = std::fs::read_link(&self.system_path).ok()?;
+
+        self.link_target
+            .as_ref()
+            .is_some_and(|recorded| recorded != &current)
+            .then_some(current)
+    }
+
+    /// how the file is applied, encrypted files and templates are always copied so that no
+    /// plaintext or unrendered template ends up on the system. Managed symlinks are always
+    /// recreated as they were recorded
+    pub fn apply_mode(&self, default: ApplyMode) -> ApplyMode {
+        if self.encrypted || self.template || self.is_link() {
+            return ApplyMode::Copy;
         }
+
+        self.mode.unwrap_or(default)
+    }
+
+    /// whether the file can be decrypted on this machine
+    pub fn key_is_available(&self, encryption: &EncryptionConfig) -> bool {
+        match self.backend.as_deref() {
+            Some(backend) => encryption.backends.contains_key(backend),
+            None => encryption.key_is_available(self.key_group.as_deref()),
+        }
+    }
+
+    /// whether the encrypted repo copy is written ASCII armored
+    pub fn armored(&self, encryption: &EncryptionConfig) -> bool {
+        self.armor.unwrap_or(encryption.armor)
+    }
+
+    /// the mode plaintext that must not leak is written with, the recorded permissions limited to
+    /// the owner so that e.g. an encrypted script stays executable
+    fn restricted_mode(&self) -> u32 {
+        self.permissions
+            .map(|permissions| permissions & 0o700)
+            .unwrap_or(DECRYPTED_FILE_MODE)
+    }
+
+    /// whether the mode of the system file differs from the recorded permissions
+    ///
+    /// files without recorded permissions, e.g. those added before permissions were tracked, or
+    /// missing system files never count as changed. Neither does the owner-only mode decrypted
+    /// and rendered files are applied with
+    pub fn permissions_changed(&self) -> Result<bool> {
+        let Some(recorded) = self.permissions else {
+            return Ok(false);
+        };
+
+        if !self.system_path.exists() {
+            return Ok(false);
+        }
+
+        let Some(current) = file_permissions(&self.system_path)? else {
+            return Ok(false);
+        };
+
+        if current == recorded {
+            return Ok(false);
+        }
+
+        Ok(current != self.restricted_mode() || !self.is_applied_restricted()?)
+    }
+
+    /// whether apply writes the file with `restricted_mode`, i.e. it is decrypted or rendered
+    fn is_applied_restricted(&self) -> Result<bool> {
+        if self.encrypted || self.template {
+            return Ok(true);
+        }
+
+        if self.is_link() || !self.repo_path.exists() {
+            return Ok(false);
+        }
+
+        Ok(read_with_placeholders(&self.repo_path)?.is_some())
     }
 }
 
-#[derive(Deserialize, Serialize, Debug, Default, Clone)]
+#[derive(Deserialize, Debug, Default, Clone)]
 pub struct Metadata {
     #[serde(skip)]
     path: PathBuf,
+    /// the root of the repo, which repo paths are stored relative to
+    #[serde(skip)]
+    repo: PathBuf,
+    /// all managed files, including the private ones once the metadata has been unsealed
     pub files: Vec<FileData>,
+    /// directories tracked as a whole, their files are part of `files`
+    #[serde(default)]
+    pub directories: Vec<DirectoryData>,
+    /// glob patterns of files to offer for management
+    #[serde(default)]
+    pub rules: Vec<TrackingRule>,
+    /// the encrypted entries of private files, directories and rules, one per key group
+    #[serde(default)]
+    sealed: Vec<SealedFiles>,
+    /// a known token per key, used to verify the configured keys before they are used
+    #[serde(default)]
+    key_checks: Vec<KeyCheck>,
+    /// the secret each key keys plaintext hashes with, see `HashKey`
+    #[serde(default)]
+    hash_keys: Vec<SealedHashKey>,
+    /// the encryption config the metadata was unsealed with, used to seal it again
+    #[serde(skip)]
+    encryption: Option<Box<EncryptionConfig>>,
+}
+
+/// The entries of all private files, directories and rules of a key group, encrypted as a whole
+#[derive(Deserialize, Serialize, Debug, Clone)]
+struct SealedFiles {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key_group: Option<String>,
+    /// ASCII armored `age` encrypted `SealedEntries`
+    files: String,
+    /// the decrypted entries, `None` until they are unsealed on this machine
+    #[serde(skip)]
+    unsealed: Option<SealedEntries>,
+}
+
+/// A known token encrypted with the key of a key group
+#[derive(Deserialize, Serialize, Debug, Clone)]
+struct KeyCheck {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key_group: Option<String>,
+    /// ASCII armored `age` encrypted `KEY_CHECK_TOKEN`
+    token: String,
+}
+
+/// The secret of a `HashKey`, encrypted with the key of a key group
+#[derive(Deserialize, Serialize, Debug, Clone)]
+struct SealedHashKey {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key_group: Option<String>,
+    /// ASCII armored `age` encrypted 32 byte secret
+    secret: String,
+}
+
+/// the plaintext of every `KeyCheck`
+const KEY_CHECK_TOKEN: &[u8] = b"conman key check";
+
+/// The plaintext form of `SealedFiles`
+#[derive(Deserialize, Serialize, Debug, Default, Clone, PartialEq)]
+struct SealedEntries {
+    files: Vec<FileData>,
+    #[serde(default, skip_serializing_if = "<[_]>::is_empty")]
+    directories: Vec<DirectoryData>,
+    #[serde(default, skip_serializing_if = "<[_]>::is_empty")]
+    rules: Vec<TrackingRule>,
+}
+
+impl SealedEntries {
+    fn is_empty(&self) -> bool {
+        self.files.is_empty() && self.directories.is_empty() && self.rules.is_empty()
+    }
+}
+
+/// The on-disk form of `Metadata`, which never contains private entries in plaintext
+#[derive(Serialize)]
+struct StoredMetadata<'a> {
+    files: Vec<FileData>,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    directories: Vec<DirectoryData>,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    rules: Vec<&'a TrackingRule>,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    sealed: &'a [SealedFiles],
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    key_checks: &'a [KeyCheck],
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    hash_keys: &'a [SealedHashKey],
 }
 
 impl Metadata {
     #[instrument]
     pub fn read(path: &PathBuf) -> Result<Self> {
-        let mut metadata = match File::open(&path) {
+        let repo = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        Self::read_in_repo(path, repo)
+    }
+
+    /// read metadata kept outside of the repo, such as the cache, whose repo paths are relative to
+    /// `repo` rather than its parent
+    #[instrument]
+    fn read_in_repo(path: &PathBuf, repo: PathBuf) -> Result<Self> {
+        let mut metadata = match File::open(path) {
             Ok(mut file) => {
                 tracing::trace!("found file metadata file");
                 let mut contents = String::new();
@@ -67,10 +471,378 @@ impl Metadata {
         };
 
         metadata.path = path.clone();
+        metadata.repo = repo;
+
+        // repo paths written before they were stored relative to the repo are absolute, which
+        // `join` keeps as they are
+        for file in metadata.files.iter_mut() {
+            file.repo_path = metadata.repo.join(&file.repo_path);
+        }
+        for directory in metadata.directories.iter_mut() {
+            directory.repo_path = metadata.repo.join(&directory.repo_path);
+        }
+
+        Ok(metadata)
+    }
+
+    /// read the metadata and unseal the entries of private files whose key is available
+    #[instrument(skip(encryption))]
+    pub fn read_unsealed(path: &PathBuf, encryption: &EncryptionConfig) -> Result<Self> {
+        let mut metadata = Self::read(path)?;
+        metadata.unseal(encryption)?;
 
         Ok(metadata)
     }
 
+    /// decrypt the entries of private files, directories and rules and manage them alongside all
+    /// other ones
+    ///
+    /// entries whose key is not available on this machine stay sealed and are persisted as-is
+    #[instrument(skip(self, encryption))]
+    pub fn unseal(&mut self, encryption: &EncryptionConfig) -> Result<()> {
+        for sealed in self.sealed.iter_mut() {
+            if sealed.unsealed.is_some() {
+                continue;
+            }
+
+            if !encryption.key_is_available(sealed.key_group.as_deref()) {
+                tracing::trace!(key_group=?sealed.key_group, "key unavailable, leaving entries sealed");
+                continue;
+            }
+
+            let key = encryption.key(sealed.key_group.as_deref())?;
+            let contents = decrypt_contents(sealed.files.as_bytes(), key).with_context(|| {
+                format!(
+                    "failed to decrypt the private entries of {}",
+                    key_description(sealed.key_group.as_deref())
+                )
+            })?;
+            let mut entries: SealedEntries = toml::from_str(std::str::from_utf8(&contents)?)?;
+
+            for file in entries.files.iter_mut() {
+                file.private = true;
+                file.repo_path = self.repo.join(&file.repo_path);
+            }
+            for directory in entries.directories.iter_mut() {
+                directory.private = true;
+                directory.repo_path = self.repo.join(&directory.repo_path);
+            }
+            for rule in entries.rules.iter_mut() {
+                rule.private = true;
+            }
+            tracing::trace!(
+                key_group=?sealed.key_group,
+                "unsealed {} entries",
+                entries.files.len() + entries.directories.len() + entries.rules.len()
+            );
+
+            self.files.extend(entries.files.iter().cloned());
+            self.directories.extend(entries.directories.iter().cloned());
+            self.rules.extend(entries.rules.iter().cloned());
+            sealed.unsealed = Some(entries);
+        }
+
+        // hash keys are only decrypted once they are used, see `hash_secret`
+        for sealed_hash_key in self.hash_keys.iter() {
+            let Ok(key) = encryption.key(sealed_hash_key.key_group.as_deref()) else {
+                continue;
+            };
+
+            let mut hash_key = key.hash_key.lock().unwrap();
+            if hash_key.secret.is_none() {
+                hash_key.sealed = Some(sealed_hash_key.secret.clone());
+            }
+        }
+
+        self.encryption = Some(Box::new(encryption.clone()));
+
+        Ok(())
+    }
+
+    /// encrypt the entries of private files, directories and rules, reusing the existing
+    /// ciphertext of key groups whose entries did not change to avoid needless churn in the repo
+    #[instrument(skip(self))]
+    fn seal(&mut self) -> Result<()> {
+        let key_groups: BTreeSet<Option<String>> = self
+            .sealed
+            .iter()
+            .map(|sealed| sealed.key_group.clone())
+            .chain(
+                self.files
+                    .iter()
+                    .filter(|file| file.private)
+                    .map(|file| file.key_group.clone()),
+            )
+            .chain(
+                self.directories
+                    .iter()
+                    .filter(|directory| directory.private)
+                    .map(|directory| directory.key_group.clone()),
+            )
+            .chain(
+                self.rules
+                    .iter()
+                    .filter(|rule| rule.private)
+                    .map(|rule| rule.key_group.clone()),
+            )
+            .collect();
+
+        let mut sealed_files = Vec::with_capacity(key_groups.len());
+
+        for key_group in key_groups.into_iter() {
+            let entries = SealedEntries {
+                files: self
+                    .files
+                    .iter()
+                    .filter(|file| file.private && file.key_group == key_group)
+                    .cloned()
+                    .collect(),
+                directories: self
+                    .directories
+                    .iter()
+                    .filter(|directory| directory.private && directory.key_group == key_group)
+                    .cloned()
+                    .collect(),
+                rules: self
+                    .rules
+                    .iter()
+                    .filter(|rule| rule.private && rule.key_group == key_group)
+                    .cloned()
+                    .collect(),
+            };
+
+            let existing = self
+                .sealed
+                .iter()
+                .find(|sealed| sealed.key_group == key_group);
+
+            if let Some(existing) = existing {
+                match existing.unsealed.as_ref() {
+                    None if entries.is_empty() => {
+                        sealed_files.push(existing.clone());
+                        continue;
+                    }
+                    None => {
+                        return Err(anyhow!(
+                            "private entries of key group '{}' are sealed with a key that is not available",
+                            key_group.as_deref().unwrap_or("default")
+                        ));
+                    }
+                    Some(unsealed) if unsealed.eq(&entries) => {
+                        sealed_files.push(existing.clone());
+                        continue;
+                    }
+                    Some(_) => {}
+                }
+            }
+
+            if entries.is_empty() {
+                continue;
+            }
+
+            let Some(encryption) = self.encryption.as_ref() else {
+                return Err(anyhow!(
+                    "metadata has to be unsealed before private entries can be stored"
+                ));
+            };
+
+            let key = encryption.key(key_group.as_deref())?;
+            let stored = Zeroizing::new(toml::to_string(&SealedEntries {
+                files: entries
+                    .files
+                    .iter()
+                    .map(|file| self.stored_file(file))
+                    .collect(),
+                directories: entries
+                    .directories
+                    .iter()
+                    .map(|directory| self.stored_directory(directory))
+                    .collect(),
+                rules: entries.rules.clone(),
+            })?);
+
+            sealed_files.push(SealedFiles {
+                key_group,
+                files: encrypt_armored(stored.as_bytes(), key)?,
+                unsealed: Some(entries),
+            });
+        }
+
+        self.sealed = sealed_files;
+        tracing::trace!("sealed private entries");
+
+        Ok(())
+    }
+
+    /// seal the private entries and the hash key of the given key group again, e.g. after its key
+    /// changed. The hash key has to be resolved with the previous key before, see
+    /// `resolve_hash_key`
+    pub fn reseal(&mut self, key_group: Option<&str>, encryption: &EncryptionConfig) -> Result<()> {
+        self.sealed
+            .retain(|sealed| sealed.key_group.as_deref() != key_group || sealed.unsealed.is_none());
+
+        let mut hash_key = encryption.key(key_group)?.hash_key.lock().unwrap();
+        if hash_key.secret.is_some() {
+            hash_key.sealed = None;
+        }
+
+        self.encryption = Some(Box::new(encryption.clone()));
+        Ok(())
+    }
+
+    /// store the hash keys generated or resealed during this run encrypted with their key
+    #[instrument(skip(self))]
+    fn seal_hash_keys(&mut self) -> Result<()> {
+        let Some(encryption) = self.encryption.as_ref() else {
+            return Ok(());
+        };
+
+        for (key_group, key) in encryption.keys() {
+            let mut hash_key = key.hash_key.lock().unwrap();
+
+            let Some(secret) = hash_key
+                .secret
+                .as_ref()
+                .filter(|_| hash_key.sealed.is_none())
+            else {
+                continue;
+            };
+
+            let sealed = encrypt_armored(secret.expose_secret(), key)?;
+
+            self.hash_keys
+                .retain(|hash_key| hash_key.key_group.as_deref() != key_group);
+            self.hash_keys.push(SealedHashKey {
+                key_group: key_group.map(str::to_string),
+                secret: sealed.clone(),
+            });
+
+            hash_key.sealed = Some(sealed);
+            tracing::trace!(key_group = key_group, "sealed hash key");
+        }
+
+        Ok(())
+    }
+
+    /// record a key check for the given key group unless there already is one
+    #[instrument(skip(self, key))]
+    pub fn ensure_key_check(&mut self, key_group: Option<&str>, key: &KeyConfig) -> Result<()> {
+        if self
+            .key_checks
+            .iter()
+            .any(|check| check.key_group.as_deref() == key_group)
+        {
+            return Ok(());
+        }
+
+        self.key_checks.push(KeyCheck {
+            key_group: key_group.map(String::from),
+            token: encrypt_armored(KEY_CHECK_TOKEN, key)?,
+        });
+        tracing::trace!("recorded key check");
+
+        Ok(())
+    }
+
+    /// replace the key check of the given key group, e.g. after its key changed
+    pub fn replace_key_check(&mut self, key_group: Option<&str>, key: &KeyConfig) -> Result<()> {
+        self.key_checks
+            .retain(|check| check.key_group.as_deref() != key_group);
+        self.ensure_key_check(key_group, key)
+    }
+
+    /// check whether a key check has been recorded for the given key group
+    pub fn has_key_check(&self, key_group: Option<&str>) -> bool {
+        self.key_checks
+            .iter()
+            .any(|check| check.key_group.as_deref() == key_group)
+    }
+
+    /// verify every key that is available on this machine against its key check
+    #[instrument(skip(self, encryption))]
+    pub fn verify_keys(&self, encryption: &EncryptionConfig) -> Result<()> {
+        for check in self.key_checks.iter() {
+            let key_group = check.key_group.as_deref();
+
+            if !encryption.key_is_available(key_group) {
+                tracing::trace!(key_group = key_group, "key unavailable, skipping key check");
+                continue;
+            }
+
+            let key = encryption.key(key_group)?;
+
+            let matches = decrypt_contents(check.token.as_bytes(), key)
+                .map(|token| token.as_slice() == KEY_CHECK_TOKEN)
+                .unwrap_or(false);
+
+            if !matches {
+                return Err(anyhow!(
+                    "the configured {} does not match the one the repo was encrypted with, check the passphrase or identity file",
+                    key_description(key_group)
+                ));
+            }
+            tracing::trace!(key_group = key_group, "verified key");
+        }
+
+        Ok(())
+    }
+
+    /// whether there are neither managed files nor sealed entries
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+            && self.sealed.is_empty()
+            && self.directories.is_empty()
+            && self.rules.is_empty()
+    }
+
+    /// serialize the metadata, private entries are only included in their sealed form
+    fn to_toml(&self) -> Result<String> {
+        let stored = StoredMetadata {
+            files: self
+                .files
+                .iter()
+                .filter(|file| !file.private)
+                .map(|file| self.stored_file(file))
+                .collect(),
+            directories: self
+                .directories
+                .iter()
+                .filter(|directory| !directory.private)
+                .map(|directory| self.stored_directory(directory))
+                .collect(),
+            rules: self.rules.iter().filter(|rule| !rule.private).collect(),
+            sealed: &self.sealed,
+            key_checks: &self.key_checks,
+            hash_keys: &self.hash_keys,
+        };
+
+        Ok(toml::to_string(&stored)?)
+    }
+
+    /// the on-disk form of a file entry, with its repo path relative to the repo
+    fn stored_file(&self, file: &FileData) -> FileData {
+        FileData {
+            repo_path: self.stored_repo_path(&file.repo_path),
+            ..file.clone()
+        }
+    }
+
+    /// the on-disk form of a directory entry, with its repo path relative to the repo
+    fn stored_directory(&self, directory: &DirectoryData) -> DirectoryData {
+        DirectoryData {
+            repo_path: self.stored_repo_path(&directory.repo_path),
+            ..directory.clone()
+        }
+    }
+
+    /// a repo path relative to the repo, so the metadata does not depend on where the repo is
+    fn stored_repo_path(&self, repo_path: &Path) -> PathBuf {
+        repo_path
+            .strip_prefix(&self.repo)
+            .unwrap_or(repo_path)
+            .to_path_buf()
+    }
+
     pub fn get_file_data_by_index(&self, index: usize) -> Option<&FileData> {
         self.files.get(index)
     }
@@ -81,10 +853,19 @@ impl Metadata {
             .find(|file| file.system_path.eq(system_path))
     }
 
-    pub fn get_file_data_where_repo_path_ends_with(&self, path: &PathBuf) -> Option<&FileData> {
+    pub fn get_file_data_by_system_path_mut(
+        &mut self,
+        system_path: &PathBuf,
+    ) -> Option<&mut FileData> {
         self.files
-            .iter()
-            .find(|file| file.repo_path.ends_with(path))
+            .iter_mut()
+            .find(|file| file.system_path.eq(system_path))
+    }
+
+    /// the file stored at the given path relative to the repo root, as reported by git
+    pub fn get_file_data_by_relative_repo_path(&self, relative_path: &Path) -> Option<&FileData> {
+        let repo_path = self.repo.join(relative_path);
+        self.files.iter().find(|file| file.repo_path == repo_path)
     }
 
     pub fn file_is_already_managed(&self, system_path: &PathBuf) -> bool {
@@ -93,7 +874,7 @@ impl Metadata {
                 return true;
             }
         }
-        return false;
+        false
     }
 
     /// manage the given `FileData`
@@ -101,6 +882,59 @@ impl Metadata {
         self.files.push(file_data);
     }
 
+    pub fn get_directory_by_system_path(&self, system_path: &Path) -> Option<&DirectoryData> {
+        self.directories
+            .iter()
+            .find(|directory| directory.system_path == system_path)
+    }
+
+    /// files matching any tracking rule that applies to this machine that are not managed yet
+    pub fn untracked_rule_matches(&self) -> Result<Vec<(&TrackingRule, PathBuf)>> {
+        let mut matches: Vec<(&TrackingRule, PathBuf)> = vec![];
+
+        for rule in self
+            .rules
+            .iter()
+            .filter(|rule| rule.conditions.apply_to_this_machine())
+        {
+            for path in rule.matching_files()?.into_iter() {
+                if !self.file_is_already_managed(&path)
+                    && !matches.iter().any(|(_, matched)| matched == &path)
+                {
+                    matches.push((rule, path));
+                }
+            }
+        }
+
+        Ok(matches)
+    }
+
+    /// manage the given `DirectoryData`, its files have to be managed separately
+    pub fn manage_directory(&mut self, directory: DirectoryData) {
+        self.directories.push(directory);
+    }
+
+    /// stop tracking a directory, returning it together with the files inside it, which are no
+    /// longer managed either
+    #[instrument(skip(self))]
+    pub fn unmanage_directory(
+        &mut self,
+        system_path: &Path,
+    ) -> Option<(DirectoryData, Vec<FileData>)> {
+        let index = self
+            .directories
+            .iter()
+            .position(|directory| directory.system_path == system_path)?;
+        let directory = self.directories.remove(index);
+
+        let (files, kept) = std::mem::take(&mut self.files)
+            .into_iter()
+            .partition(|file| directory.contains(&file.system_path));
+        self.files = kept;
+
+        Some((directory, files))
+    }
+
     /// only remove the file from the internal metadata storage without removing the actual file
     /// from disk
     #[instrument(skip(self, system_path))]
@@ -120,8 +954,11 @@ impl Metadata {
     }
 
     #[instrument(skip(self))]
-    pub fn persist(&self) -> Result<()> {
-        let metadata = toml::to_string(self)?;
+    pub fn persist(&mut self) -> Result<()> {
+        self.seal()?;
+        self.seal_hash_keys()?;
+
+        let metadata = self.to_toml()?;
 
         std::fs::write(&self.path, metadata)?;
         tracing::trace!(path=?self.path, "wrote metadata to disk");
@@ -130,9 +967,22 @@ impl Metadata {
     }
 }
 
+/// describe a key in messages to the user
+fn key_description(key_group: Option<&str>) -> String {
+    match key_group {
+        Some(key_group) => format!("key of key group '{key_group}'"),
+        None => "default key".to_string(),
+    }
+}
+
 /// remove a managed file from the internal metadata storage and on disk
 #[instrument(skip(file_data))]
 pub fn remove_from_repo(file_data: &FileData) -> Result<()> {
+    if file_data.is_link() {
+        tracing::trace!("managed symlinks have no repo copy");
+        return Ok(());
+    }
+
     // remove the file from the local git repo
     match std::fs::remove_file(&file_data.repo_path) {
         Ok(()) => {
@@ -149,19 +999,38 @@ pub fn remove_from_repo(file_data: &FileData) -> Result<()> {
 
 /// read the current metadata and the cached metadata and compare the two returning
 /// a verdict to action upon
-#[instrument(skip(metadata_path, cache_path))]
-pub fn verify_cache(metadata_path: &PathBuf, cache_path: &PathBuf) -> Result<CacheVerdict> {
-    let cache = Metadata::read(cache_path)?;
-    let metadata = Metadata::read(metadata_path)?;
-
-    if cache.files.is_empty() && !metadata.files.is_empty() {
+///
+/// private entries are only unsealed, and therefore compared, if their ciphertext differs
+#[instrument(skip(metadata_path, cache_path, encryption))]
+pub fn verify_cache(
+    metadata_path: &PathBuf,
+    cache_path: &PathBuf,
+    encryption: &EncryptionConfig,
+) -> Result<CacheVerdict> {
+    let mut metadata = Metadata::read(metadata_path)?;
+    let mut cache = Metadata::read_in_repo(cache_path, metadata.repo.clone())?;
+
+    if cache.is_empty() && !metadata.is_empty() {
         return Ok(CacheVerdict::FullPopulate(metadata));
     }
 
-    if cache.files.is_empty() && metadata.files.is_empty() {
+    if cache.is_empty() && metadata.is_empty() {
         return Ok(CacheVerdict::DoNothing);
     }
 
+    let sealed_differ = cache.sealed.len() != metadata.sealed.len()
+        || cache
+            .sealed
+            .iter()
+            .zip(metadata.sealed.iter())
+            .any(|(theirs, ours)| theirs.key_group != ours.key_group || theirs.files != ours.files);
+
+    if sealed_differ {
+        tracing::trace!("sealed entries differ, unsealing");
+        cache.unseal(encryption)?;
+        metadata.unseal(encryption)?;
+    }
+
     let dangling_files = dangling_cached_files(&metadata, &cache);
 
     if dangling_files.is_empty() {
@@ -177,6 +1046,7 @@ fn dangling_cached_files(metadata: &Metadata, cache: &Metadata) -> Vec<FileData>
     cache
         .files
         .iter()
+        .filter(|theirs| theirs.applies_to_this_machine())
         .filter(|theirs| {
             metadata
                 .files
@@ -184,14 +1054,14 @@ fn dangling_cached_files(metadata: &Metadata, cache: &Metadata) -> Vec<FileData>
                 .find(|ours| ours.system_path.eq(&theirs.system_path))
                 .is_none()
         })
-        .map(|theirs| theirs.clone())
+        .cloned()
         .collect()
 }
 
 /// writes the given metadata to the specified cache path
 #[instrument(skip(metadata, cache_path))]
 pub fn write_cache(metadata: &Metadata, cache_path: &PathBuf) -> Result<()> {
-    let cache = toml::to_string(metadata)?;
+    let cache = metadata.to_toml()?;
     tracing::trace!("serialized branch cache");
 
     std::fs::write(cache_path, cache)?;
@@ -201,67 +1071,932 @@ pub fn write_cache(metadata: &Metadata, cache_path: &PathBuf) -> Result<()> {
 }
 
 /// performs a file content copy from a `FileData`'s `repo_path` to it's `system_path`
-#[instrument(skip(file_data, passphrase))]
-pub fn copy_from_repo(file_data: &FileData, passphrase: &str) -> Result<()> {
-    if file_data.encrypted {
-        copy_repo_encrypted(file_data, passphrase)?;
+///
+/// secret placeholders in plaintext files are filled in from the `vault`
+#[instrument(skip(file_data, encryption, vault))]
+pub fn copy_from_repo(
+    file_data: &FileData,
+    encryption: &EncryptionConfig,
+    vault: &Vault,
+) -> Result<()> {
+    remove_link_to_repo(file_data)?;
+
+    if let Some(link_target) = file_data.link_target.as_ref() {
+        replace_with_symlink(link_target, &file_data.system_path)?;
+    } else if file_data.encrypted {
+        copy_repo_encrypted(file_data, encryption)?;
+    } else if let Some(contents) = read_with_placeholders(&file_data.repo_path)? {
+        copy_repo_rendered(file_data, &contents, vault)?;
     } else {
         copy_any_unencrypted(&file_data.repo_path, &file_data.system_path)?;
+
+        if let Some(permissions) = file_data.permissions {
+            set_file_permissions(&file_data.system_path, permissions)?;
+        }
     }
     Ok(())
 }
 
-/// performs a file content copy from a `FileData`'s encrypted `repo_path` to it's unencrypted `system_path`
-#[instrument(skip(file_data, passphrase))]
-pub fn copy_repo_encrypted(file_data: &FileData, passphrase: &str) -> Result<()> {
-    let passphrase = SecretString::from(passphrase.to_string());
+/// puts a `FileData` in place at its `system_path` according to its `ApplyMode`
+#[instrument(skip(file_data, config, vault))]
+pub fn apply_from_repo(file_data: &FileData, config: &Config, vault: &Vault) -> Result<()> {
+    if file_data.template {
+        return copy_repo_template(file_data, config, vault);
+    }
 
-    let encrypted_file_contents = read_file_contents(&file_data.repo_path)?;
+    match file_data.apply_mode(config.mode) {
+        ApplyMode::Copy => copy_from_repo(file_data, &config.encryption, vault),
+        ApplyMode::Symlink => link_from_repo(file_data, &config.encryption, vault),
+    }
+}
 
-    let decryptor = Decryptor::new(&encrypted_file_contents[..])?;
+/// renders the template in a `FileData`'s `repo_path` into its `system_path`, decrypting it first
+/// if it is encrypted
+///
+/// rendered encrypted templates or templates using secrets are only accessible by their owner,
+/// like decrypted files
+#[instrument(skip(file_data, config, vault))]
+fn copy_repo_template(file_data: &FileData, config: &Config, vault: &Vault) -> Result<()> {
+    let source = read_template(file_data, &config.encryption)?;
+    let rendered = template::render(&source, config, vault)?;
 
-    let mut decrypted_file_contents = vec![];
-    let mut reader =
-        decryptor.decrypt(std::iter::once(&age::scrypt::Identity::new(passphrase) as _))?;
+    remove_link_to_repo(file_data)?;
 
-    reader.read_to_end(&mut decrypted_file_contents)?;
+    let mode = if file_data.encrypted || vault::has_placeholders(&source) {
+        Some(file_data.restricted_mode())
+    } else {
+        file_data.permissions
+    };
 
-    std::fs::write(&file_data.system_path, decrypted_file_contents)?;
+    write_atomically(&file_data.system_path, mode, |destination| {
+        destination.write_all(rendered.as_bytes())?;
+        Ok(())
+    })?;
 
+    tracing::trace!("copied and rendered template");
     Ok(())
 }
 
-/// read the file contents at the provided `path`
-#[instrument]
-fn read_file_contents(path: &PathBuf) -> Result<Vec<u8>> {
-    let mut reader = File::open(&path)?;
-    let mut file_contents: Vec<u8> = vec![];
-
-    std::io::copy(&mut reader, &mut file_contents)?;
+/// render the template of a `FileData` the way `apply` would write it
+#[instrument(skip(file_data, config, vault))]
+pub fn render_repo_file(
+    file_data: &FileData,
+    config: &Config,
+    vault: &Vault,
+) -> Result<Zeroizing<String>> {
+    let source = read_template(file_data, &config.encryption)?;
+    template::render(&source, config, vault)
+}
 
-    tracing::trace!("read file contents");
-    Ok(file_contents)
+/// read the unrendered template of a `FileData`, decrypting it if needed
+pub fn read_template(
+    file_data: &FileData,
+    encryption: &EncryptionConfig,
+) -> Result<Zeroizing<String>> {
+    let contents = match file_data.encrypted {
+        true => decrypt_repo_file(file_data, encryption)?,
+        false => Zeroizing::new(std::fs::read(&file_data.repo_path)?),
+    };
+
+    into_zeroizing_string(contents).ok_or_else(|| {
+        anyhow!(
+            "'{}' is not valid UTF-8 and can not be a template",
+            file_data.system_path.display()
+        )
+    })
 }
 
-/// set up the encryptor used for `age` file encryption
-fn init_encryptor(secret: &str) -> Encryptor {
-    let passphrase = SecretString::from(secret.to_string());
-    Encryptor::with_user_passphrase(passphrase)
+/// turn a plaintext buffer into a string without copying it, `None` if it is not valid UTF-8
+fn into_zeroizing_string(mut contents: Zeroizing<Vec<u8>>) -> Option<Zeroizing<String>> {
+    // checked up front, the error of `String::from_utf8` would hand the bytes back unprotected
+    std::str::from_utf8(&contents).ok()?;
+
+    let contents = std::mem::take(&mut *contents);
+    String::from_utf8(contents).ok().map(Zeroizing::new)
 }
 
-/// performs a file content copy from a `FileData`'s `system_path` to it's `repo_path`
-#[instrument(skip(file_data, passphrase))]
-pub fn copy_from_system(file_data: &FileData, passphrase: &str) -> Result<()> {
+/// replace the template of a `FileData` with `source`, encrypting it if needed
+#[instrument(skip(file_data, encryption, source))]
+pub fn write_template(
+    file_data: &mut FileData,
+    encryption: &EncryptionConfig,
+    source: &str,
+) -> Result<()> {
     if file_data.encrypted {
-        let encryptor = init_encryptor(passphrase);
-        copy_system_encrypted(encryptor, &file_data.system_path, &file_data.repo_path)?;
+        let repo_path = file_data.repo_path.clone();
+        file_data.plaintext_hash =
+            encrypt_into(file_data, encryption, source.as_bytes(), &repo_path)?;
     } else {
-        copy_any_unencrypted(&file_data.system_path, &file_data.repo_path)?;
+        std::fs::write(&file_data.repo_path, source)?;
     }
+
+    tracing::trace!("wrote template");
     Ok(())
 }
 
-/// perform a simple copy of the file at source into the local conman git repo
+/// writing through a symlink into the repo would overwrite the repo copy itself, so remove such a
+/// link before anything is written to the `system_path`
+fn remove_link_to_repo(file_data: &FileData) -> Result<()> {
+    if is_linked_to_repo(file_data) {
+        std::fs::remove_file(&file_data.system_path)?;
+        tracing::trace!("removed symlink to the repo copy");
+    }
+    Ok(())
+}
+
+/// makes a `FileData`'s `system_path` a symlink to its `repo_path`, replacing whatever is there
+///
+/// encrypted files and files with secret placeholders can not be used from the repo as they are,
+/// they are copied instead
+#[instrument(skip(file_data, encryption, vault))]
+pub fn link_from_repo(
+    file_data: &FileData,
+    encryption: &EncryptionConfig,
+    vault: &Vault,
+) -> Result<()> {
+    if file_data.encrypted || read_with_placeholders(&file_data.repo_path)?.is_some() {
+        tracing::trace!("falling back to copying");
+        return copy_from_repo(file_data, encryption, vault);
+    }
+
+    if is_linked_to_repo(file_data) {
+        tracing::trace!("already linked to the repo copy");
+        return Ok(());
+    }
+
+    replace_with_symlink(&file_data.repo_path, &file_data.system_path)?;
+
+    tracing::trace!("linked system path to the repo copy");
+    Ok(())
+}
+
+/// replace whatever is at `path` with a symlink to `target`
+///
+/// the link is created next to `path` and renamed over it, like `write_atomically` does for files
+#[instrument]
+fn replace_with_symlink(target: &Path, path: &Path) -> Result<()> {
+    let Some(file_name) = path.file_name() else {
+        return Err(anyhow!("'{}' is not a file", path.display()));
+    };
+
+    let mut temp_file_name = OsString::from(".");
+    temp_file_name.push(file_name);
+    temp_file_name.push(".conman-tmp");
+    let temp_path = path.with_file_name(temp_file_name);
+
+    if temp_path.symlink_metadata().is_ok() {
+        std::fs::remove_file(&temp_path)?;
+    }
+
+    create_symlink(target, &temp_path)?;
+    if let Err(e) = std::fs::rename(&temp_path, path) {
+        std::fs::remove_file(&temp_path)?;
+        return Err(e.into());
+    }
+
+    Ok(())
+}
+
+/// replaces a `FileData`'s `system_path` that is linked to its `repo_path` with a plain copy, so
+/// that it survives the repo copy being removed or encrypted
+#[instrument(skip(file_data))]
+pub fn unlink_from_repo(file_data: &FileData) -> Result<()> {
+    if !is_linked_to_repo(file_data) {
+        return Ok(());
+    }
+
+    std::fs::remove_file(&file_data.system_path)?;
+    copy_any_unencrypted(&file_data.repo_path, &file_data.system_path)?;
+
+    tracing::trace!("replaced symlink with a copy of the repo copy");
+    Ok(())
+}
+
+/// whether a `FileData`'s `system_path` is a symlink to its `repo_path`, i.e. it was applied with
+/// `ApplyMode::Symlink`
+pub fn is_linked_to_repo(file_data: &FileData) -> bool {
+    std::fs::read_link(&file_data.system_path).is_ok_and(|target| target == file_data.repo_path)
+}
+
+#[cfg(unix)]
+fn create_symlink(target: &Path, link: &Path) -> Result<()> {
+    std::os::unix::fs::symlink(target, link)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn create_symlink(_target: &Path, link: &Path) -> Result<()> {
+    Err(anyhow!(
+        "can not link '{}', symlinks are only supported on unix",
+        link.display()
+    ))
+}
+
+/// writes the plaintext repo copy of a `FileData` to its `system_path` with the secret
+/// placeholders in `contents` filled in
+///
+/// like decrypted files, the rendered file is only accessible by its owner
+#[instrument(skip(file_data, contents, vault))]
+fn copy_repo_rendered(file_data: &FileData, contents: &str, vault: &Vault) -> Result<()> {
+    let rendered = vault.render(contents)?;
+
+    write_atomically(
+        &file_data.system_path,
+        Some(file_data.restricted_mode()),
+        |destination| {
+            destination.write_all(rendered.as_bytes())?;
+            Ok(())
+        },
+    )?;
+
+    tracing::trace!("copied and rendered secrets into file contents");
+    Ok(())
+}
+
+/// read a plaintext file if it references secrets of the vault
+fn read_with_placeholders(path: &Path) -> Result<Option<Zeroizing<String>>> {
+    let Some(contents) = into_zeroizing_string(Zeroizing::new(std::fs::read(path)?)) else {
+        return Ok(None);
+    };
+
+    Ok(vault::has_placeholders(&contents).then_some(contents))
+}
+
+/// read a plaintext system file with the values of all secrets of the `vault` replaced by their
+/// placeholders, or `None` if the file contains none of them
+#[instrument(skip(vault))]
+pub fn read_redacted(path: &Path, vault: &Vault) -> Result<Option<String>> {
+    if !vault.exists() {
+        return Ok(None);
+    }
+
+    let Some(contents) = into_zeroizing_string(Zeroizing::new(std::fs::read(path)?)) else {
+        return Ok(None);
+    };
+
+    let redacted = vault.redact(&contents)?;
+    Ok((redacted != *contents).then_some(redacted))
+}
+
+/// performs a file content copy from a `FileData`'s encrypted `repo_path` to it's unencrypted `system_path`
+///
+/// the contents are decrypted in chunks of `STREAM_BUFFER_SIZE` bytes and the `system_path` is
+/// only replaced once decryption has finished. The plaintext is only ever written to a file that
+/// only its owner can access, `DECRYPTED_FILE_MODE` unless permissions were recorded
+#[instrument(skip(file_data, encryption))]
+pub fn copy_repo_encrypted(file_data: &FileData, encryption: &EncryptionConfig) -> Result<()> {
+    let mut reader = open_decrypted(file_data, encryption)?;
+
+    write_atomically(
+        &file_data.system_path,
+        Some(file_data.restricted_mode()),
+        |destination| {
+            stream(&mut reader, destination)?;
+            Ok(())
+        },
+    )?;
+
+    tracing::trace!("copied and decrypted file contents");
+    Ok(())
+}
+
+/// decrypts the contents of a `FileData`'s encrypted `repo_path` into memory
+///
+/// the returned buffer is zeroed once it is dropped
+#[instrument(skip(file_data, encryption))]
+pub fn decrypt_repo_file(
+    file_data: &FileData,
+    encryption: &EncryptionConfig,
+) -> Result<Zeroizing<Vec<u8>>> {
+    let mut reader = open_decrypted(file_data, encryption)?;
+
+    // age does not compress, its plaintext is never larger than the ciphertext. External backends
+    // may compress, e.g. gpg does by default, so their plaintext is of unknown size and the buffer
+    // grows as it is read
+    let capacity = match file_data.backend {
+        None => std::fs::metadata(&file_data.repo_path)?.len() as usize,
+        Some(_) => STREAM_BUFFER_SIZE,
+    };
+    let decrypted_file_contents = read_zeroizing(&mut reader, capacity)?;
+
+    tracing::trace!("decrypted file contents");
+    Ok(decrypted_file_contents)
+}
+
+/// test-decrypt a `FileData`'s encrypted `repo_path` without keeping the plaintext
+#[instrument(skip(file_data, encryption))]
+pub fn verify_repo_file(file_data: &FileData, encryption: &EncryptionConfig) -> Result<()> {
+    let mut reader = open_decrypted(file_data, encryption)?;
+    stream(&mut reader, &mut std::io::sink())?;
+
+    Ok(())
+}
+
+/// open a reader over the plaintext of a `FileData`'s encrypted `repo_path`
+#[instrument(skip(file_data, encryption))]
+fn open_decrypted(file_data: &FileData, encryption: &EncryptionConfig) -> Result<Box<dyn Read>> {
+    backend::for_file(file_data, encryption)?.decrypt(&file_data.repo_path)
+}
+
+/// encrypts `contents` into `destination` with the backend of a `FileData`, returning the keyed
+/// hash of the plaintext for files encrypted with `age`
+#[instrument(skip(file_data, encryption, contents))]
+pub fn encrypt_into(
+    file_data: &FileData,
+    encryption: &EncryptionConfig,
+    contents: &[u8],
+    destination: &Path,
+) -> Result<Option<String>> {
+    let backend = backend::for_file(file_data, encryption)?;
+
+    write_atomically(destination, None, |destination| {
+        backend.encrypt(&mut &contents[..], destination)
+    })?;
+
+    hash_key(file_data, encryption)?
+        .map(|key| plaintext_hash(contents, key))
+        .transpose()
+}
+
+/// the key the plaintext hash of a `FileData` is computed with
+///
+/// files of external backends have no `age` key to hash with, so they are compared by contents
+fn hash_key<'a>(
+    file_data: &FileData,
+    encryption: &'a EncryptionConfig,
+) -> Result<Option<&'a KeyConfig>> {
+    if file_data.backend.is_some() {
+        return Ok(None);
+    }
+
+    Ok(Some(encryption.key(file_data.key_group.as_deref())?))
+}
+
+/// encrypts the given contents in memory using the given key, optionally ASCII armored
+#[instrument(skip(contents, key))]
+pub fn encrypt_contents(contents: &[u8], key: &KeyConfig, armor: bool) -> Result<Vec<u8>> {
+    let encryptor = init_encryptor(key)?;
+
+    let mut encrypted_contents = vec![];
+    let armored_writer = ArmoredWriter::wrap_output(&mut encrypted_contents, armor_format(armor))?;
+    let mut writer = encryptor.wrap_output(armored_writer)?;
+    writer.write_all(contents)?;
+    writer.finish()?.finish()?;
+
+    tracing::trace!("encrypted file contents");
+    Ok(encrypted_contents)
+}
+
+/// encrypts the given contents in memory into ASCII armored `age` output
+#[instrument(skip(contents, key))]
+pub(crate) fn encrypt_armored(contents: &[u8], key: &KeyConfig) -> Result<String> {
+    let armored_contents = encrypt_contents(contents, key, true)?;
+
+    Ok(String::from_utf8(armored_contents)?)
+}
+
+pub(crate) fn armor_format(armor: bool) -> Format {
+    if armor {
+        Format::AsciiArmor
+    } else {
+        Format::Binary
+    }
+}
+
+/// decrypts in-memory `age` output, which may be ASCII armored, using the given key
+///
+/// the returned buffer is zeroed once it is dropped
+#[instrument(skip(encrypted_contents, key))]
+pub(crate) fn decrypt_contents(
+    encrypted_contents: &[u8],
+    key: &KeyConfig,
+) -> Result<Zeroizing<Vec<u8>>> {
+    let decryptor = Decryptor::new(ArmoredReader::new(encrypted_contents))?;
+
+    let identities = init_identities(key, decryptor.is_scrypt())?;
+
+    let mut reader = decryptor.decrypt(identities.iter().map(|identity| identity.as_ref()))?;
+    // age does not compress, the plaintext is never larger than the ciphertext
+    let decrypted_contents = read_zeroizing(&mut reader, encrypted_contents.len())?;
+
+    tracing::trace!("decrypted contents");
+    Ok(decrypted_contents)
+}
+
+/// unix mode decrypted files are written with, readable and writable by the owner only
+pub(crate) const DECRYPTED_FILE_MODE: u32 = 0o600;
+
+/// the mode bits that are recorded and restored
+const PERMISSION_BITS: u32 = 0o777;
+
+/// size of the buffer file contents are streamed through while encrypting, decrypting or hashing
+pub(crate) const STREAM_BUFFER_SIZE: usize = 64 * 1024;
+
+/// copy everything from `reader` to `writer` through a buffer of `STREAM_BUFFER_SIZE` bytes,
+/// returning the number of bytes copied
+pub(crate) fn stream(
+    reader: &mut (impl Read + ?Sized),
+    writer: &mut (impl Write + ?Sized),
+) -> Result<u64> {
+    // the buffer may hold plaintext, so it is zeroed once it is dropped
+    let mut buffer = Zeroizing::new(vec![0; STREAM_BUFFER_SIZE]);
+    let mut copied = 0;
+
+    loop {
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+
+        writer.write_all(&buffer[..read])?;
+        copied += read as u64;
+    }
+
+    tracing::trace!(bytes = copied, "streamed file contents");
+    Ok(copied)
+}
+
+/// read everything from `reader` into a buffer that is zeroed once it is dropped, allocating
+/// `capacity` bytes up front
+pub(crate) fn read_zeroizing(
+    reader: &mut (impl Read + ?Sized),
+    capacity: usize,
+) -> Result<Zeroizing<Vec<u8>>> {
+    let mut buffer = ZeroizingBuffer(Zeroizing::new(Vec::with_capacity(capacity)));
+    stream(reader, &mut buffer)?;
+
+    Ok(buffer.0)
+}
+
+/// A growable buffer for plaintext
+///
+/// a plain `Vec` leaves copies of its contents behind in the memory it frees while growing, this
+/// one moves its contents into a larger buffer itself and zeroes the one it outgrew
+struct ZeroizingBuffer(Zeroizing<Vec<u8>>);
+
+impl Write for ZeroizingBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let required = self.0.len() + buf.len();
+
+        if required > self.0.capacity() {
+            let mut grown = Zeroizing::new(Vec::with_capacity(required.max(self.0.capacity() * 2)));
+            grown.extend_from_slice(&self.0);
+            self.0 = grown;
+        }
+
+        self.0.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// write the file at `path` by writing a temporary file next to it and renaming it over `path`
+/// once `write` succeeded, so that `path` is never left partially written
+///
+/// symlinks are followed. The file is created with `mode` if given, before anything is written to
+/// it, otherwise the permissions of an existing file are kept
+#[instrument(skip(write))]
+pub(crate) fn write_atomically(
+    path: &Path,
+    mode: Option<u32>,
+    write: impl FnOnce(&mut File) -> Result<()>,
+) -> Result<()> {
+    stage_file(path, mode, write)?.commit()?;
+
+    tracing::trace!("wrote file atomically");
+    Ok(())
+}
+
+/// A fully written temporary file that replaces its destination once committed, and is removed
+/// if it is dropped before that
+pub(crate) struct StagedFile {
+    temp_path: PathBuf,
+    path: PathBuf,
+    committed: bool,
+}
+
+impl StagedFile {
+    /// rename the temporary file over its destination
+    pub(crate) fn commit(mut self) -> Result<()> {
+        std::fs::rename(&self.temp_path, &self.path)?;
+        self.committed = true;
+
+        tracing::trace!(path=?self.path, "committed staged file");
+        Ok(())
+    }
+}
+
+impl Drop for StagedFile {
+    fn drop(&mut self) {
+        if !self.committed && self.temp_path.exists() {
+            let _ = std::fs::remove_file(&self.temp_path);
+        }
+    }
+}
+
+/// commit several staged files all or nothing, the files committed before one fails are restored
+/// to their previous contents
+#[instrument(skip(staged_files))]
+pub(crate) fn commit_all(staged_files: Vec<StagedFile>) -> Result<()> {
+    // read up front, so a file that can not be read aborts before anything was replaced
+    let mut previous_contents = Vec::with_capacity(staged_files.len());
+    for staged_file in staged_files.iter() {
+        previous_contents.push(match std::fs::read(&staged_file.path) {
+            Ok(contents) => Some(contents),
+            Err(e) if e.kind() == ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        });
+    }
+
+    let mut committed = vec![];
+    for (staged_file, previous) in staged_files.into_iter().zip(previous_contents) {
+        let path = staged_file.path.clone();
+
+        if let Err(error) = staged_file.commit() {
+            restore_committed(committed);
+            return Err(error.context(format!("failed to replace '{}'", path.display())));
+        }
+
+        committed.push((path, previous));
+    }
+
+    Ok(())
+}
+
+/// put back the previous contents of files replaced by `commit_all`, in reverse order
+///
+/// this is best effort, failures are logged and the remaining files are still restored
+fn restore_committed(committed: Vec<(PathBuf, Option<Vec<u8>>)>) {
+    for (path, previous) in committed.into_iter().rev() {
+        let restored = match previous {
+            Some(contents) => write_atomically(&path, None, |file| Ok(file.write_all(&contents)?)),
+            None => std::fs::remove_file(&path).map_err(Into::into),
+        };
+
+        match restored {
+            Ok(()) => tracing::trace!(?path, "restored replaced file"),
+            Err(error) => tracing::warn!("failed to restore '{}': {error}", path.display()),
+        }
+    }
+}
+
+/// write the temporary file `write_atomically` renames over `path`, leaving `path` untouched
+/// until the returned `StagedFile` is committed. Used to replace several files all or nothing
+#[instrument(skip(write))]
+pub(crate) fn stage_file(
+    path: &Path,
+    mode: Option<u32>,
+    write: impl FnOnce(&mut File) -> Result<()>,
+) -> Result<StagedFile> {
+    let path = std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+
+    let Some(file_name) = path.file_name() else {
+        return Err(anyhow!("'{}' is not a file", path.display()));
+    };
+
+    let mut temp_file_name = OsString::from(".");
+    temp_file_name.push(file_name);
+    temp_file_name.push(".conman-tmp");
+
+    let staged = StagedFile {
+        temp_path: path.with_file_name(temp_file_name),
+        path,
+        committed: false,
+    };
+
+    let mut temp_file = match mode {
+        Some(mode) => create_with_mode(&staged.temp_path, mode)?,
+        None => {
+            let temp_file = File::create(&staged.temp_path)?;
+            if let Ok(metadata) = std::fs::metadata(&staged.path) {
+                temp_file.set_permissions(metadata.permissions())?;
+            }
+            temp_file
+        }
+    };
+
+    write(&mut temp_file)?;
+    temp_file.sync_all()?;
+
+    tracing::trace!(path=?staged.temp_path, "staged file");
+    Ok(staged)
+}
+
+/// create (or truncate) the file at `path` with the given unix `mode`
+///
+/// the mode is applied before the file is returned, so no contents are ever readable with looser
+/// permissions, even if a stale file already existed at `path`
+fn create_with_mode(path: &Path, mode: u32) -> Result<File> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+        options.mode(mode);
+        let file = options.open(path)?;
+        file.set_permissions(std::fs::Permissions::from_mode(mode))?;
+        Ok(file)
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = mode;
+        Ok(options.open(path)?)
+    }
+}
+
+/// whether users other than the owner can read the file at `path`
+///
+/// always false on platforms without unix permissions, or if the file does not exist
+pub fn is_readable_by_others(path: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        std::fs::metadata(path)
+            .map(|metadata| metadata.permissions().mode() & 0o044 != 0)
+            .unwrap_or(false)
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        false
+    }
+}
+
+/// the unix mode bits of the file at `path`, `None` on platforms without unix permissions
+///
+/// only the permission bits are tracked, setuid, setgid and sticky bits are never recorded or
+/// restored
+pub fn file_permissions(path: &Path) -> Result<Option<u32>> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        Ok(Some(
+            std::fs::metadata(path)?.permissions().mode() & PERMISSION_BITS,
+        ))
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        Ok(None)
+    }
+}
+
+/// set the unix mode bits of the file at `path`, does nothing on platforms without unix
+/// permissions
+fn set_file_permissions(path: &Path, permissions: u32) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        let permissions = permissions & PERMISSION_BITS;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(permissions))?;
+        tracing::trace!(
+            permissions = format!("{permissions:o}"),
+            "set file permissions"
+        );
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = (path, permissions);
+        Ok(())
+    }
+}
+
+/// set up the identities used for `age` file decryption
+///
+/// passphrase protected files are decrypted with the passphrase, which is only resolved (and
+/// possibly prompted for) at this point. Any other file is decrypted with the identity file
+#[instrument(skip(key))]
+pub(crate) fn init_identities(
+    key: &KeyConfig,
+    passphrase_protected: bool,
+) -> Result<Vec<Box<dyn Identity>>> {
+    if passphrase_protected {
+        let passphrase = key.passphrase()?;
+        tracing::trace!("using passphrase identity");
+        return Ok(vec![Box::new(age::scrypt::Identity::new(passphrase))]);
+    }
+
+    let Some(identity_file) = key.identity_file.as_ref() else {
+        return Err(anyhow!(
+            "file is encrypted to a public key but its key has no `identity_file` set"
+        ));
+    };
+
+    let identities: Vec<Box<dyn Identity>> = match read_identity_file(identity_file)? {
+        KeyFile::Age(identity_file) => identity_file.into_identities()?,
+        KeyFile::Ssh(identity) => vec![Box::new(identity.with_callbacks(PromptCallbacks))],
+    };
+    tracing::trace!("read identities from identity file");
+
+    Ok(identities)
+}
+
+/// set up the recipients new files are encrypted to
+///
+/// explicitly configured recipients take precedence over the ones derived from the identity file
+#[instrument(skip(key))]
+fn init_recipients(key: &KeyConfig) -> Result<Vec<Box<dyn Recipient + Send>>> {
+    if !key.recipients.is_empty() {
+        return key
+            .recipients
+            .iter()
+            .map(|recipient| parse_recipient(recipient))
+            .collect();
+    }
+
+    let Some(identity_file) = key.identity_file.as_ref() else {
+        return Ok(vec![]);
+    };
+
+    let recipients = match read_identity_file(identity_file)? {
+        KeyFile::Age(identity_file) => identity_file.to_recipients()?,
+        KeyFile::Ssh(identity) => {
+            let recipient = age::ssh::Recipient::try_from(identity).map_err(|e| {
+                anyhow!(
+                    "unable to derive recipient from '{}': {e:?}",
+                    identity_file.display()
+                )
+            })?;
+            vec![Box::new(recipient) as Box<dyn Recipient + Send>]
+        }
+    };
+    tracing::trace!(
+        "derived {} recipient(s) from identity file",
+        recipients.len()
+    );
+
+    Ok(recipients)
+}
+
+/// parse a single public key into an `age` recipient
+///
+/// both native `age1...` keys and `ssh-ed25519`/`ssh-rsa` public keys are supported
+fn parse_recipient(recipient: &str) -> Result<Box<dyn Recipient + Send>> {
+    if recipient.starts_with("ssh-") {
+        let recipient = recipient
+            .parse::<age::ssh::Recipient>()
+            .map_err(|e| anyhow!("invalid ssh recipient '{recipient}': {e:?}"))?;
+        return Ok(Box::new(recipient));
+    }
+
+    let recipient = recipient
+        .parse::<age::x25519::Recipient>()
+        .map_err(|e| anyhow!("invalid recipient '{recipient}': {e}"))?;
+    Ok(Box::new(recipient))
+}
+
+/// The contents of a configured identity file
+enum KeyFile {
+    Age(IdentityFile<age::NoCallbacks>),
+    Ssh(age::ssh::Identity),
+}
+
+/// read an identity file which is either a native `age` identity file or an SSH private key
+#[instrument]
+fn read_identity_file(path: &Path) -> Result<KeyFile> {
+    let file_name = path.to_string_lossy().to_string();
+
+    let ssh_identity =
+        age::ssh::Identity::from_buffer(BufReader::new(File::open(path)?), Some(file_name.clone()));
+
+    match ssh_identity {
+        Ok(age::ssh::Identity::Unsupported(key)) => Err(anyhow!(
+            "unsupported ssh key '{file_name}': {key:?}. Only ssh-ed25519 and ssh-rsa keys are supported"
+        )),
+        Ok(identity) => {
+            tracing::trace!("identity file is an ssh private key");
+            Ok(KeyFile::Ssh(identity))
+        }
+        Err(_) => {
+            tracing::trace!("identity file is an age identity file");
+            Ok(KeyFile::Age(IdentityFile::from_file(file_name)?))
+        }
+    }
+}
+
+/// Interactive callbacks `age` uses when it needs user input, e.g. for passphrase protected SSH
+/// keys
+#[derive(Clone)]
+struct PromptCallbacks;
+
+impl Callbacks for PromptCallbacks {
+    fn display_message(&self, message: &str) {
+        eprintln!("{message}");
+    }
+
+    fn confirm(&self, message: &str, yes_string: &str, no_string: Option<&str>) -> Option<bool> {
+        let prompt = match no_string {
+            Some(no_string) => format!("{message} ({yes_string}/{no_string})"),
+            None => format!("{message} ({yes_string})"),
+        };
+
+        Confirm::with_theme(&ColorfulTheme::default())
+            .with_prompt(prompt)
+            .interact()
+            .ok()
+    }
+
+    fn request_public_string(&self, description: &str) -> Option<String> {
+        Input::with_theme(&ColorfulTheme::default())
+            .with_prompt(description)
+            .interact_text()
+            .ok()
+    }
+
+    fn request_passphrase(&self, description: &str) -> Option<SecretString> {
+        Password::with_theme(&ColorfulTheme::default())
+            .with_prompt(description)
+            .interact()
+            .ok()
+            .map(SecretString::from)
+    }
+}
+
+/// set up the encryptor used for `age` file encryption
+pub(crate) fn init_encryptor(key: &KeyConfig) -> Result<Encryptor> {
+    let recipients = init_recipients(key)?;
+
+    if !recipients.is_empty() {
+        tracing::trace!("encrypting to {} recipient(s)", recipients.len());
+        let encryptor = Encryptor::with_recipients(
+            recipients
+                .iter()
+                .map(|recipient| recipient.as_ref() as &dyn Recipient),
+        )?;
+        return Ok(encryptor);
+    }
+
+    tracing::trace!("encrypting with passphrase");
+    let passphrase = key.passphrase()?;
+    Ok(Encryptor::with_user_passphrase(passphrase))
+}
+
+/// performs a file content copy from a `FileData`'s `system_path` to it's `repo_path`
+///
+/// the `plaintext_hash` of encrypted files is updated to match the copied contents, and the
+/// `permissions` to the mode of the system file. Values of secrets in the `vault` are replaced by
+/// their placeholders in plaintext files
+#[instrument(skip(file_data, encryption, vault))]
+pub fn copy_from_system(
+    file_data: &mut FileData,
+    encryption: &EncryptionConfig,
+    vault: &Vault,
+) -> Result<()> {
+    if file_data.is_link() {
+        file_data.link_target =
+            Some(std::fs::read_link(&file_data.system_path).with_context(|| {
+                format!(
+                    "'{}' is no longer a symlink",
+                    file_data.system_path.display()
+                )
+            })?);
+        tracing::trace!(target = ?file_data.link_target, "recorded link target");
+        return Ok(());
+    }
+
+    // edits to a linked file already are edits to the repo copy
+    if is_linked_to_repo(file_data) {
+        tracing::trace!("system path is linked to the repo copy, nothing to copy");
+        file_data.permissions = file_permissions(&file_data.system_path)?;
+        return Ok(());
+    }
+
+    if file_data.encrypted {
+        let backend = backend::for_file(file_data, encryption)?;
+        let plaintext_hash = copy_system_encrypted(
+            backend.as_ref(),
+            hash_key(file_data, encryption)?,
+            &file_data.system_path,
+            &file_data.repo_path,
+        )?;
+        file_data.plaintext_hash = plaintext_hash;
+    } else if let Some(redacted) = read_redacted(&file_data.system_path, vault)? {
+        tracing::trace!("replaced secrets with placeholders");
+        std::fs::write(&file_data.repo_path, redacted)?;
+    } else {
+        copy_any_unencrypted(&file_data.system_path, &file_data.repo_path)?;
+    }
+
+    file_data.permissions = file_permissions(&file_data.system_path)?;
+    Ok(())
+}
+
+/// perform a simple copy of the file at source into the local conman git repo
 #[instrument]
 fn copy_any_unencrypted(from: &PathBuf, to: &PathBuf) -> Result<()> {
     tracing::trace!("no encryption selected, performing simple file copy");
@@ -270,30 +2005,257 @@ fn copy_any_unencrypted(from: &PathBuf, to: &PathBuf) -> Result<()> {
     Ok(())
 }
 
-/// perform an encrypted copy of the file at source into the local conman git repo
-#[instrument(skip(encryptor))]
-fn copy_system_encrypted(encryptor: Encryptor, from: &PathBuf, to: &PathBuf) -> Result<()> {
+/// perform an encrypted copy of the file at source into the local conman git repo, returning the
+/// keyed hash of the plaintext if a `hash_key` is given
+#[instrument(skip(backend, hash_key))]
+fn copy_system_encrypted(
+    backend: &dyn EncryptionBackend,
+    hash_key: Option<&KeyConfig>,
+    from: &PathBuf,
+    to: &PathBuf,
+) -> Result<Option<String>> {
     tracing::trace!("preparing file copy with encryption");
 
-    let file_contents = read_file_contents(&from)?;
-
-    // prepare the destination file
-    let mut destination_file = File::create(&to)?;
+    let mut hasher = hash_key.map(PlaintextHasher::new).transpose()?;
+    let mut source = File::open(from)?;
 
     tracing::trace!("encrypting file contents");
-    // write encrypted file contents to the destination file
-    let mut writer = encryptor.wrap_output(&mut destination_file)?;
-    writer.write_all(&file_contents)?;
-    writer.finish()?;
+    write_atomically(to, None, |destination| match hasher.as_mut() {
+        Some(hasher) => backend.encrypt(&mut hasher.reader(&mut source), destination),
+        None => backend.encrypt(&mut source, destination),
+    })?;
 
     tracing::trace!("copied and encrypted file contents");
 
-    Ok(())
+    Ok(hasher.map(PlaintextHasher::finish))
+}
+
+/// domain separation for the key the plaintext hashes are computed with
+const PLAINTEXT_HASH_CONTEXT: &[u8] = b"conman plaintext hash v1";
+/// domain separation for the key opaque file names are computed with
+const OPAQUE_NAME_CONTEXT: &[u8] = b"conman opaque name v1";
+
+/// the random secret the plaintext hashes of files encrypted with `key` are keyed with
+///
+/// it is decrypted from the metadata on first use, or generated if the key has none yet, and
+/// stored in the metadata the next time it is persisted
+#[instrument(skip(key))]
+fn hash_secret(key: &KeyConfig) -> Result<Zeroizing<[u8; 32]>> {
+    let mut hash_key = key.hash_key.lock().unwrap();
+
+    if let Some(secret) = hash_key.secret.as_ref() {
+        return Ok(Zeroizing::new(*secret.expose_secret()));
+    }
+
+    let mut secret = Box::new([0; 32]);
+    match hash_key.sealed.as_ref() {
+        Some(sealed) => {
+            let decrypted = decrypt_contents(sealed.as_bytes(), key)
+                .context("failed to decrypt the hash key")?;
+            if decrypted.len() != secret.len() {
+                return Err(anyhow!("the stored hash key is malformed"));
+            }
+            secret.copy_from_slice(&decrypted);
+            tracing::trace!("decrypted hash key");
+        }
+        None => {
+            rand::RngCore::fill_bytes(&mut rand::rng(), secret.as_mut_slice());
+            tracing::trace!("generated hash key");
+        }
+    }
+
+    let hash_secret = Zeroizing::new(*secret);
+    hash_key.secret = Some(SecretBox::new(secret));
+
+    Ok(hash_secret)
+}
+
+/// make sure the hash key of `key` is decrypted, e.g. before the key is replaced
+pub(crate) fn resolve_hash_key(key: &KeyConfig) -> Result<()> {
+    hash_secret(key).map(|_| ())
+}
+
+/// Incrementally computes the keyed hash of a plaintext
+///
+/// the hash is keyed with the random secret of the given key, see `hash_secret`, so it reveals
+/// nothing about the plaintext to anyone without that key
+struct PlaintextHasher(Hmac<Sha256>);
+
+impl PlaintextHasher {
+    fn new(key: &KeyConfig) -> Result<Self> {
+        Self::with_context(key, PLAINTEXT_HASH_CONTEXT)
+    }
+
+    fn with_context(key: &KeyConfig, context: &[u8]) -> Result<Self> {
+        let secret = hash_secret(key)?;
+
+        let mut hash_key = Hmac::<Sha256>::new_from_slice(secret.as_slice())?;
+        hash_key.update(context);
+        let hash_key: Zeroizing<[u8; 32]> = Zeroizing::new(hash_key.finalize().into_bytes().into());
+
+        Ok(Self(Hmac::<Sha256>::new_from_slice(hash_key.as_slice())?))
+    }
+
+    /// wrap `inner` so that everything read from it is hashed
+    fn reader<R: Read>(&mut self, inner: R) -> HashingReader<'_, R> {
+        HashingReader {
+            inner,
+            hasher: self,
+        }
+    }
+
+    fn update(&mut self, contents: &[u8]) {
+        self.0.update(contents);
+    }
+
+    /// the hex encoded hash of everything hashed so far
+    fn finish(self) -> String {
+        self.0
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+}
+
+/// A reader that hashes everything read through it
+struct HashingReader<'a, R> {
+    inner: R,
+    hasher: &'a mut PlaintextHasher,
+}
+
+impl<R: Read> Read for HashingReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+/// compute the keyed hash of the given plaintext
+#[instrument(skip(contents, key))]
+pub fn plaintext_hash(contents: &[u8], key: &KeyConfig) -> Result<String> {
+    let mut hasher = PlaintextHasher::new(key)?;
+    hasher.update(contents);
+
+    Ok(hasher.finish())
+}
+
+/// derive the opaque repo file name of a private file from its system path
+///
+/// the name is stable, so the same file always ends up at the same repo path, but reveals nothing
+/// about the system path to anyone without the key
+#[instrument(skip(key))]
+pub fn opaque_file_name(system_path: &Path, key: &KeyConfig) -> Result<String> {
+    let mut hasher = PlaintextHasher::with_context(key, OPAQUE_NAME_CONTEXT)?;
+    hasher.update(system_path.as_os_str().as_encoded_bytes());
+
+    let mut name = hasher.finish();
+    name.truncate(32);
+
+    Ok(name)
+}
+
+/// compute the keyed hash of everything `reader` yields, without holding it in memory
+fn plaintext_hash_of(reader: impl Read, key: &KeyConfig) -> Result<String> {
+    let mut hasher = PlaintextHasher::new(key)?;
+    stream(&mut hasher.reader(reader), &mut std::io::sink())?;
+
+    Ok(hasher.finish())
+}
+
+/// check whether the system copy of a managed file differs from its repo copy
+///
+/// encrypted files are compared by their plaintext, using the recorded `plaintext_hash` where
+/// possible and falling back to decrypting the repo copy, e.g. for files collected before hashes
+/// were recorded or hashed with a different identity. Plaintext files with secret placeholders are
+/// compared with the secrets of the `vault` replaced by their placeholders. Managed symlinks are
+/// compared by their target
+#[instrument(skip(file_data, encryption, vault))]
+pub fn system_file_was_updated(
+    file_data: &FileData,
+    encryption: &EncryptionConfig,
+    vault: &Vault,
+) -> Result<bool> {
+    if let Some(link_target) = file_data.link_target.as_ref() {
+        return Ok(std::fs::read_link(&file_data.system_path).ok().as_ref() != Some(link_target));
+    }
+
+    if !file_data.encrypted {
+        if !source_was_updated(&file_data.system_path, &file_data.repo_path)? {
+            return Ok(false);
+        }
+
+        let Some(repo_contents) = read_with_placeholders(&file_data.repo_path)? else {
+            return Ok(true);
+        };
+
+        tracing::trace!("comparing against the repo copy with placeholders");
+        let system_contents = Zeroizing::new(match read_redacted(&file_data.system_path, vault)? {
+            Some(redacted) => redacted,
+            None => std::fs::read_to_string(&file_data.system_path)?,
+        });
+
+        return Ok(system_contents != repo_contents);
+    }
+
+    let Some(key) = hash_key(file_data, encryption)? else {
+        tracing::trace!("comparing against the decrypted repo copy");
+        return contents_differ(
+            File::open(&file_data.system_path)?,
+            open_decrypted(file_data, encryption)?,
+        );
+    };
+
+    let system_hash = plaintext_hash_of(File::open(&file_data.system_path)?, key)?;
+
+    if file_data
+        .plaintext_hash
+        .as_ref()
+        .is_some_and(|recorded_hash| recorded_hash.eq(&system_hash))
+    {
+        tracing::trace!("plaintext hash matches, file is unchanged");
+        return Ok(false);
+    }
+
+    tracing::trace!("comparing against the decrypted repo copy");
+    let repo_hash = plaintext_hash_of(open_decrypted(file_data, encryption)?, key)?;
+
+    Ok(system_hash != repo_hash)
+}
+
+/// compare everything two readers yield, without holding either in memory
+fn contents_differ(left: impl Read, right: impl Read) -> Result<bool> {
+    let mut left = BufReader::with_capacity(STREAM_BUFFER_SIZE, left);
+    let mut right = BufReader::with_capacity(STREAM_BUFFER_SIZE, right);
+
+    loop {
+        let left_buffer = left.fill_buf()?;
+        let right_buffer = right.fill_buf()?;
+
+        let length = left_buffer.len().min(right_buffer.len());
+        if length == 0 {
+            return Ok(left_buffer.len() != right_buffer.len());
+        }
+
+        if left_buffer[..length] != right_buffer[..length] {
+            return Ok(true);
+        }
+
+        left.consume(length);
+        right.consume(length);
+    }
 }
 
 /// Compares two files' metadata to check for differences
 #[instrument(skip(source, dest))]
 pub fn source_was_updated(source: &PathBuf, dest: &PathBuf) -> Result<bool> {
+    if std::fs::read_link(source).is_ok_and(|target| &target == dest) {
+        tracing::trace!("source is a symlink to the destination");
+        return Ok(false);
+    }
+
     let source_metadata = std::fs::metadata(source)?;
     let dest_metadata = std::fs::metadata(dest)?;
 
@@ -320,14 +2282,35 @@ pub fn source_was_updated(source: &PathBuf, dest: &PathBuf) -> Result<bool> {
     Ok(false)
 }
 
-pub fn canonicalize_paths(files: &Vec<PathBuf>) -> Vec<PathBuf> {
+pub fn canonicalize_paths(files: &[PathBuf]) -> Vec<PathBuf> {
     // FIXME: errors when canonicalizing non-existing paths
     files
-        .into_iter()
-        .map(|path| std::fs::canonicalize(path).unwrap())
+        .iter()
+        .map(|path| canonicalize_path(path).unwrap())
         .collect()
 }
 
+/// make `path` absolute and resolve the symlinks in its parent directories, but not a symlink at
+/// `path` itself, which may be a managed file applied as a symlink into the repo
+///
+/// only the parent directory has to exist, e.g. for managed files that were not applied yet
+pub fn canonicalize_path(path: &Path) -> Result<PathBuf> {
+    let (Some(parent), Some(file_name)) = (path.parent(), path.file_name()) else {
+        return Ok(std::fs::canonicalize(path)?);
+    };
+
+    if path.exists() && !path.is_symlink() {
+        return Ok(std::fs::canonicalize(path)?);
+    }
+
+    let parent = match parent.as_os_str().is_empty() {
+        true => std::env::current_dir()?,
+        false => std::fs::canonicalize(parent)?,
+    };
+
+    Ok(parent.join(file_name))
+}
+
 pub fn canonicalize_optional_paths(maybe_files: Option<&Vec<PathBuf>>) -> Option<Vec<PathBuf>> {
     maybe_files.map(|files| canonicalize_paths(files))
 }
@@ -351,7 +2334,7 @@ where
     Ok(path)
 }
 
-fn serialize_metadata_path<S>(path: &PathBuf, ser: S) -> Result<S::Ok, S::Error>
+fn serialize_metadata_path<S>(path: &Path, ser: S) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
 {
@@ -365,3 +2348,41 @@ where
 
     ser.serialize_str(&path_string)
 }
+
+fn deserialize_permissions<'de, D>(de: D) -> Result<Option<u32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let permissions = String::deserialize(de)?;
+
+    u32::from_str_radix(&permissions, 8)
+        .map(|permissions| Some(permissions & PERMISSION_BITS))
+        .map_err(|_| serde::de::Error::custom(format!("invalid permissions '{permissions}'")))
+}
+
+fn serialize_permissions<S>(permissions: &Option<u32>, ser: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match permissions {
+        Some(permissions) => ser.serialize_str(&format!("{permissions:04o}")),
+        None => ser.serialize_none(),
+    }
+}
+
+fn deserialize_optional_metadata_path<'de, D>(de: D) -> Result<Option<PathBuf>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserialize_metadata_path(de).map(Some)
+}
+
+fn serialize_optional_metadata_path<S>(path: &Option<PathBuf>, ser: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match path {
+        Some(path) => serialize_metadata_path(path, ser),
+        None => ser.serialize_none(),
+    }
+}
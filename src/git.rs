@@ -89,7 +89,7 @@ impl Repo {
     pub fn set_upstream(&self, branch_name: &str) -> Result<()> {
         let mut branch = self.inner.find_branch(branch_name, BranchType::Local)?;
 
-        if let Err(_) = branch.upstream() {
+        if branch.upstream().is_err() {
             branch.set_upstream(Some(branch_name))?;
             tracing::trace!("set upstream for branch '{branch_name}' to 'origin/{branch_name}'");
         }
@@ -251,7 +251,7 @@ impl Repo {
     pub fn commit_changes(&self, commit_message: String) -> Result<()> {
         let mut index = self.inner.index()?;
 
-        index.add_all(&["."], git2::IndexAddOption::DEFAULT, None)?;
+        index.add_all(["."], git2::IndexAddOption::DEFAULT, None)?;
         tracing::trace!("staged all files");
 
         let oid = index.write_tree()?;
@@ -466,7 +466,7 @@ impl Repo {
     #[instrument(skip(self, config))]
     pub fn pull(&self, config: &Config) -> Result<()> {
         let remote_name = "origin";
-        let mut remote = self.inner.find_remote(&remote_name)?;
+        let mut remote = self.inner.find_remote(remote_name)?;
 
         let fetch_commit = self.fetch(config, &mut remote)?;
 
@@ -506,6 +506,8 @@ impl Repo {
     fn status_entries(&self) -> Result<Statuses<'_>> {
         let mut status_options = StatusOptions::new();
         status_options.include_untracked(true);
+        // new files are nested in new directories, which should not be reported as a whole
+        status_options.recurse_untracked_dirs(true);
 
         let status_entries = self.inner.statuses(Some(&mut status_options))?;
         tracing::trace!("got status entries");
@@ -600,7 +602,7 @@ impl Repo {
     }
 
     #[instrument(skip(self, changes_to_reset))]
-    pub fn reset(&self, changes_to_reset: &Vec<(StatusChange, FileData)>) -> Result<()> {
+    pub fn reset(&self, changes_to_reset: &[(StatusChange, FileData)]) -> Result<()> {
         let mut checkout_opts = CheckoutBuilder::new();
 
         checkout_opts.force();
@@ -638,7 +640,7 @@ mod tests {
 
     impl Repo {
         pub fn create_at_path(path: &PathBuf) -> Self {
-            let repo = Repository::init(&path).unwrap();
+            let repo = Repository::init(path).unwrap();
 
             let repo = Self { inner: repo };
 
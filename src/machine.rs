@@ -0,0 +1,115 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+/// the values `std::env::consts::OS` can take, the only operating systems a file can be limited to
+pub const OPERATING_SYSTEMS: &[&str] = &[
+    "linux",
+    "macos",
+    "ios",
+    "android",
+    "freebsd",
+    "dragonfly",
+    "netbsd",
+    "openbsd",
+    "illumos",
+    "solaris",
+    "haiku",
+    "windows",
+];
+
+/// Conditions limiting the machines a file is managed on, shared by files and the directories and
+/// tracking rules that add them
+///
+/// stored inline with the entry they belong to, an entry without conditions applies everywhere
+#[derive(Deserialize, Serialize, Debug, Default, Clone, PartialEq)]
+pub struct Conditions {
+    /// hostnames of the only machines the file is managed on
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hosts: Vec<String>,
+    /// the only operating system the file is managed on, one of `OPERATING_SYSTEMS`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub os: Option<String>,
+    /// a command that has to be installed for the file to be managed, e.g. `sway`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub requires_command: Option<String>,
+}
+
+impl Conditions {
+    /// whether all conditions are met on this machine
+    pub fn apply_to_this_machine(&self) -> bool {
+        (self.hosts.is_empty() || is_any_host(&self.hosts))
+            && self
+                .os
+                .as_ref()
+                .is_none_or(|os| os.eq_ignore_ascii_case(self::os()))
+            && self
+                .requires_command
+                .as_ref()
+                .is_none_or(|command| has_command(command))
+    }
+
+    /// reject conditions that could never be met, e.g. `osx` instead of `macos`
+    pub fn validate(&self) -> Result<()> {
+        if let Some(os) = self.os.as_ref() {
+            if !OPERATING_SYSTEMS
+                .iter()
+                .any(|known| known.eq_ignore_ascii_case(os))
+            {
+                return Err(anyhow!(
+                    "unknown operating system '{os}', expected one of {}",
+                    OPERATING_SYSTEMS.join(", ")
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// the hostname of this machine, empty if it can not be determined
+pub fn hostname() -> String {
+    whoami::fallible::hostname().unwrap_or_default()
+}
+
+/// the operating system, e.g. `linux` or `macos`
+pub fn os() -> &'static str {
+    std::env::consts::OS
+}
+
+/// whether this machine is one of `hosts`, comparing either the full hostname or the part before
+/// the first `.`, e.g. `laptop` for `laptop.local`
+pub fn is_any_host(hosts: &[String]) -> bool {
+    let hostname = hostname();
+    let short_hostname = hostname.split('.').next().unwrap_or_default();
+
+    hosts.iter().any(|host| {
+        host.eq_ignore_ascii_case(&hostname) || host.eq_ignore_ascii_case(short_hostname)
+    })
+}
+
+/// whether `command` is an executable file in one of the directories of `$PATH`
+pub fn has_command(command: &str) -> bool {
+    let Some(path) = std::env::var_os("PATH") else {
+        return false;
+    };
+
+    std::env::split_paths(&path).any(|directory| is_executable(&directory.join(command)))
+}
+
+fn is_executable(path: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        std::fs::metadata(path)
+            .map(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+            .unwrap_or(false)
+    }
+
+    #[cfg(not(unix))]
+    {
+        path.is_file()
+    }
+}
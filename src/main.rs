@@ -1,13 +1,20 @@
 use args::Args;
 use clap::Parser;
-use ops::Operation;
+use ops::{exec::ExitCode, Operation};
 
 mod args;
+mod backend;
 mod config;
+mod directory;
 mod file;
 mod git;
+mod machine;
+mod merge;
 mod ops;
 mod paths;
+mod scan;
+mod template;
+mod vault;
 
 fn main() {
     tracing_subscriber::fmt::init();
@@ -34,6 +41,11 @@ fn main() {
     }
 
     if let Ok(Err(err)) = task_handle.join() {
+        // `conman exec` exits with the exit code of the command it ran
+        if let Some(ExitCode(code)) = err.downcast_ref::<ExitCode>() {
+            std::process::exit(*code);
+        }
+
         eprintln!("ERROR: {err:?}");
     }
 }
@@ -0,0 +1,336 @@
+use std::ops::Range;
+
+use similar::{DiffTag, TextDiff};
+
+const CONFLICT_START: &str = "<<<<<<< template\n";
+const CONFLICT_SEPARATOR: &str = "=======\n";
+const CONFLICT_END: &str = ">>>>>>> system\n";
+
+/// The template with the edits made to its rendered system copy merged back into it
+pub struct Merge {
+    pub template: String,
+    /// number of edits that touch templated lines, marked like a git merge conflict with the
+    /// template lines first and the edited system lines second
+    pub conflicts: usize,
+}
+
+/// A change to a range of template lines
+struct Edit {
+    template: Range<usize>,
+    edited: Range<usize>,
+    conflict: bool,
+}
+
+/// A range of rendered lines and the edited lines that replaced them
+struct Chunk {
+    rendered: Range<usize>,
+    edited: Range<usize>,
+    changed: bool,
+}
+
+/// merge the changes between `rendered`, the output of `template`, and `edited`, the system copy
+/// the user edited, into `template`
+///
+/// lines of the template that are rendered as they are can be edited directly. Changes to lines
+/// produced by template expressions or blocks, or insertions next to them, can not be mapped back
+/// to the template and become conflicts instead
+pub fn merge(template: &str, rendered: &str, edited: &str) -> Merge {
+    let rendering = TextDiff::from_lines(template, rendered);
+    let editing = TextDiff::from_lines(rendered, edited);
+
+    let template_lines = rendering.old_slices();
+    let rendered_lines = rendering.new_slices();
+    let edited_lines = editing.new_slices();
+
+    // the template line each rendered line is a literal copy of
+    let mut sources = vec![None; rendered_lines.len()];
+    for op in rendering.ops() {
+        let (tag, template_range, rendered_range) = op.as_tag_tuple();
+        if tag == DiffTag::Equal {
+            for (template_line, rendered_line) in template_range.zip(rendered_range) {
+                sources[rendered_line] = Some(template_line);
+            }
+        }
+    }
+
+    let mut chunks = vec![];
+    for op in editing.ops() {
+        let (tag, rendered_range, edited_range) = op.as_tag_tuple();
+        match tag {
+            DiffTag::Equal => chunks.extend(rendered_range.zip(edited_range).map(
+                |(rendered, edited)| Chunk {
+                    rendered: rendered..rendered + 1,
+                    edited: edited..edited + 1,
+                    changed: false,
+                },
+            )),
+            _ => chunks.push(Chunk {
+                rendered: rendered_range,
+                edited: edited_range,
+                changed: true,
+            }),
+        }
+    }
+
+    let mut edits: Vec<Edit> = vec![];
+    let mut i = 0;
+    while i < chunks.len() {
+        let chunk = &chunks[i];
+        if !chunk.changed {
+            i += 1;
+            continue;
+        }
+
+        if let Some(template_range) =
+            literal_source(&chunk.rendered, &sources, template_lines.len())
+        {
+            edits.push(Edit {
+                template: template_range,
+                edited: chunk.edited.clone(),
+                conflict: false,
+            });
+            i += 1;
+            continue;
+        }
+
+        // widen the conflict to the closest literal lines around it
+        let (mut first, mut last) = (i, i);
+        while chunks[first].rendered.start > 0
+            && sources[chunks[first].rendered.start - 1].is_none()
+        {
+            first -= 1;
+        }
+        while chunks[last].rendered.end < rendered_lines.len()
+            && sources[chunks[last].rendered.end].is_none()
+        {
+            last += 1;
+        }
+
+        let start = match chunks[first].rendered.start {
+            0 => 0,
+            start => sources[start - 1].unwrap() + 1,
+        };
+        let end = match chunks[last].rendered.end {
+            end if end == rendered_lines.len() => template_lines.len(),
+            end => sources[end].unwrap(),
+        };
+
+        let edit = Edit {
+            template: start..end,
+            edited: chunks[first].edited.start..chunks[last].edited.end,
+            conflict: true,
+        };
+
+        // conflicts widened over the same templated lines are one conflict
+        match edits.last_mut() {
+            Some(previous) if previous.conflict && previous.template.end >= edit.template.start => {
+                previous.template.end = previous.template.end.max(edit.template.end);
+                previous.edited.start = previous.edited.start.min(edit.edited.start);
+                previous.edited.end = previous.edited.end.max(edit.edited.end);
+            }
+            _ => edits.push(edit),
+        }
+
+        i = last + 1;
+    }
+
+    let mut merged = String::with_capacity(template.len());
+    let mut conflicts = 0;
+    let mut cursor = 0;
+
+    for edit in edits.iter() {
+        let start = edit.template.start.max(cursor);
+        merged.extend(template_lines[cursor..start].iter().copied());
+
+        if edit.conflict {
+            conflicts += 1;
+            push_marker(&mut merged, CONFLICT_START);
+            merged.extend(template_lines[start..edit.template.end].iter().copied());
+            push_marker(&mut merged, CONFLICT_SEPARATOR);
+            merged.extend(edited_lines[edit.edited.clone()].iter().copied());
+            push_marker(&mut merged, CONFLICT_END);
+        } else {
+            merged.extend(edited_lines[edit.edited.clone()].iter().copied());
+        }
+
+        cursor = edit.template.end.max(start);
+    }
+    merged.extend(template_lines[cursor..].iter().copied());
+
+    Merge {
+        template: merged,
+        conflicts,
+    }
+}
+
+/// whether `contents` still contain conflict markers left by `merge`
+pub fn has_conflict_markers(contents: &str) -> bool {
+    contents.lines().any(|line| {
+        [CONFLICT_START, CONFLICT_SEPARATOR, CONFLICT_END]
+            .iter()
+            .any(|marker| line == marker.trim_end())
+    })
+}
+
+/// the template lines the `rendered` lines are literal copies of, or `None` if any of them is
+/// produced by the template. Insertions need literal lines with nothing hidden in between around
+/// them, e.g. no `{% endif %}` that renders to nothing
+fn literal_source(
+    rendered: &Range<usize>,
+    sources: &[Option<usize>],
+    template_len: usize,
+) -> Option<Range<usize>> {
+    if rendered.is_empty() {
+        let before = match rendered.start {
+            0 => 0,
+            start => sources[start - 1]? + 1,
+        };
+        let after = match rendered.start {
+            start if start == sources.len() => template_len,
+            start => sources[start]?,
+        };
+
+        return (before == after).then_some(before..before);
+    }
+
+    let first = sources[rendered.start]?;
+    for (offset, line) in rendered.clone().enumerate() {
+        if sources[line]? != first + offset {
+            return None;
+        }
+    }
+
+    Some(first..first + rendered.len())
+}
+
+/// start a conflict marker on a line of its own, even if the last line has no newline
+fn push_marker(merged: &mut String, marker: &str) {
+    if !merged.is_empty() && !merged.ends_with('\n') {
+        merged.push('\n');
+    }
+    merged.push_str(marker);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_merges(template: &str, rendered: &str, edited: &str, merged: &str, conflicts: usize) {
+        let merge = merge(template, rendered, edited);
+        assert_eq!(merged, merge.template);
+        assert_eq!(conflicts, merge.conflicts);
+        assert_eq!(conflicts > 0, has_conflict_markers(&merge.template));
+    }
+
+    #[test]
+    fn literal_lines_are_edited_directly() {
+        assert_merges(
+            "a\n{{ x }}\nb\n",
+            "a\n1\nb\n",
+            "a\n1\nB\n",
+            "a\n{{ x }}\nB\n",
+            0,
+        );
+        // between literal lines with nothing hidden in between
+        assert_merges(
+            "a\nb\n{{ x }}\n",
+            "a\nb\n1\n",
+            "a\nnew\nb\n1\n",
+            "a\nnew\nb\n{{ x }}\n",
+            0,
+        );
+    }
+
+    #[test]
+    fn templated_lines_conflict() {
+        assert_merges(
+            "a\n{{ x }}\nb\n",
+            "a\n1\nb\n",
+            "a\n2\nb\n",
+            "a\n<<<<<<< template\n{{ x }}\n=======\n2\n>>>>>>> system\nb\n",
+            1,
+        );
+    }
+
+    #[test]
+    fn lines_inside_blocks() {
+        let template = "a\n{% if x %}\nb\n{% endif %}\nc\n";
+
+        // literal lines inside a block are still literal
+        assert_merges(
+            template,
+            "a\nb\nc\n",
+            "a\nB\nc\n",
+            "a\n{% if x %}\nB\n{% endif %}\nc\n",
+            0,
+        );
+
+        // an insertion next to a tag that renders to nothing could go on either side of it
+        assert_merges(
+            template,
+            "a\nb\nc\n",
+            "a\nb\nnew\nc\n",
+            "a\n{% if x %}\nb\n<<<<<<< template\n{% endif %}\n=======\nnew\n>>>>>>> system\nc\n",
+            1,
+        );
+    }
+
+    #[test]
+    fn edits_of_loops_conflict_with_the_whole_loop() {
+        assert_merges(
+            "{% for i in xs %}\n{{ i }}\n{% endfor %}\nend\n",
+            "1\n2\nend\n",
+            "1\n3\nend\n",
+            "<<<<<<< template\n{% for i in xs %}\n{{ i }}\n{% endfor %}\n=======\n1\n3\n>>>>>>> system\nend\n",
+            1,
+        );
+
+        // widened up to the literal line before the loop and the end of the template
+        assert_merges(
+            "start\n{% for i in xs %}\n{{ i }}\n{% endfor %}\n",
+            "start\n1\n2\n",
+            "start\n1\n3\n",
+            "start\n<<<<<<< template\n{% for i in xs %}\n{{ i }}\n{% endfor %}\n=======\n1\n3\n>>>>>>> system\n",
+            1,
+        );
+    }
+
+    #[test]
+    fn deletions() {
+        assert_merges(
+            "a\n{{ x }}\nb\nc\n",
+            "a\n1\nb\nc\n",
+            "a\n1\nc\n",
+            "a\n{{ x }}\nc\n",
+            0,
+        );
+        assert_merges(
+            "a\n{{ x }}\nb\n",
+            "a\n1\nb\n",
+            "a\nb\n",
+            "a\n<<<<<<< template\n{{ x }}\n=======\n>>>>>>> system\nb\n",
+            1,
+        );
+    }
+
+    #[test]
+    fn input_without_trailing_newline() {
+        assert_merges("a\n{{ x }}", "a\n1", "A\n1", "A\n{{ x }}", 0);
+        assert_merges(
+            "a\n{{ x }}\nb",
+            "a\n1\nb",
+            "a\n1\nb\nc",
+            "a\n{{ x }}\nb\nc",
+            0,
+        );
+
+        // conflict markers always start on a line of their own
+        assert_merges(
+            "a\n{{ x }}",
+            "a\n1",
+            "a\n2",
+            "a\n<<<<<<< template\n{{ x }}\n=======\n2\n>>>>>>> system\n",
+            1,
+        );
+    }
+}
@@ -1,54 +1,236 @@
 use std::path::PathBuf;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use crossbeam_channel::Sender;
 
 use crate::{
-    config::Config,
-    file::{self, FileData, Metadata},
+    config::{ApplyMode, Config},
+    directory,
+    file::{self, DirectoryData, FileData, Metadata},
+    machine::Conditions,
     paths::Paths,
-    report,
+    report, scan,
+    vault::Vault,
 };
 
 use super::{Message, Runnable};
 
+#[derive(Default)]
 pub struct AddOp {
     pub files: Vec<PathBuf>,
     pub encrypt: bool,
+    pub key_group: Option<String>,
+    pub allow_secrets: bool,
+    pub armor: bool,
+    pub backend: Option<String>,
+    /// how the file(s) are applied, `Config::mode` if not given
+    pub mode: Option<ApplyMode>,
+    /// manage symlinks themselves instead of the files they point to
+    pub link: bool,
+    /// store the file(s) as templates rendered on apply, see `template::render`
+    pub template: bool,
+    /// only manage the file(s) on these machines, see `FileData::applies_to_this_machine`
+    pub hosts: Vec<String>,
+    /// only manage the file(s) on this operating system
+    pub os: Option<String>,
+    /// only manage the file(s) on machines where this command is installed
+    pub requires_command: Option<String>,
 }
 
 impl Runnable for AddOp {
+    fn touches_encrypted_files(&self) -> bool {
+        self.encrypt || self.key_group.is_some() || self.backend.is_some()
+    }
+
     fn run(&self, config: Config, paths: Paths, sender: Option<Sender<Message>>) -> Result<()> {
         if self.files.is_empty() {
             report!(sender, "No file(s) specified!");
             return Ok(());
         }
 
-        let mut metadata = Metadata::read(&paths.metadata)?;
+        // fail early on unknown key groups, before anything is copied
+        let key = config.encryption.key(self.key_group.as_deref())?;
+
+        let conditions = self.conditions();
+        conditions.validate()?;
+
+        let encrypt = self.touches_encrypted_files();
+        if encrypt && self.link {
+            return Err(anyhow!(
+                "symlinks are managed as links, they can not be encrypted"
+            ));
+        }
+        if self.template && self.link {
+            return Err(anyhow!(
+                "symlinks are managed as links, they can not be templates"
+            ));
+        }
+        // only files encrypted with age have a key to derive opaque names from
+        let private = encrypt && config.encryption.privacy && self.backend.is_none();
+
+        let mut metadata = Metadata::read_unsealed(&paths.metadata, &config.encryption)?;
+        let vault = Vault::open(&paths.vault, &config.encryption);
 
         let sources = file::canonicalize_paths(&self.files);
 
+        // directories are tracked as a whole, the files inside them are managed individually
+        let mut directories = vec![];
+        let mut candidates = vec![];
         for source in sources.into_iter() {
+            if source.is_symlink() && !self.link {
+                report!(
+                    sender,
+                    "'{}' is a symlink, managing the file it points to. Use `--link` to manage the link itself",
+                    source.display()
+                );
+            }
+
+            if !source.is_dir() || (self.link && source.is_symlink()) {
+                candidates.push((source, None));
+                continue;
+            }
+
+            // files `collect` adds to the directory later would not be templates
+            if self.template {
+                return Err(anyhow!(
+                    "'{}' is a directory, only individual files can be templates",
+                    source.display()
+                ));
+            }
+
+            if metadata.get_directory_by_system_path(&source).is_some() {
+                report!(
+                    sender,
+                    "'{}' is already tracked, skipping",
+                    source.display()
+                );
+                continue;
+            }
+
+            let index = directories.len();
+            candidates.extend(
+                directory::walk(&source)?
+                    .into_iter()
+                    .map(|file| (file, Some(index))),
+            );
+
+            directories.push(DirectoryData {
+                repo_path: paths.repo_local_file_path(&source)?,
+                system_path: source,
+                encrypted: encrypt,
+                key_group: self.key_group.clone(),
+                backend: self.backend.clone(),
+                armor: self.armor.then_some(true),
+                mode: self.mode,
+                conditions: conditions.clone(),
+                private,
+            });
+        }
+
+        // plaintext files are scanned for secrets before anything is copied
+        let mut allowed_secrets = vec![];
+        let mut findings = vec![];
+        for (source, _) in candidates.iter() {
+            let source_findings = if encrypt || (self.link && source.is_symlink()) {
+                vec![]
+            } else {
+                scan::scan_redacted(source, &[], &vault)?
+            };
+
+            if !source_findings.is_empty() && !self.allow_secrets {
+                findings.push((source.as_path(), source_findings));
+                continue;
+            }
+
+            allowed_secrets.push(
+                source_findings
+                    .into_iter()
+                    .map(|finding| finding.fingerprint)
+                    .collect::<Vec<_>>(),
+            );
+        }
+
+        if !findings.is_empty() {
+            return Err(anyhow!(
+                "{}\nuse `--encrypt` to store the file(s) encrypted, or `--allow-secrets` if they are safe to store in plaintext",
+                scan::describe_findings(
+                    findings
+                        .iter()
+                        .map(|(path, findings)| (*path, findings.as_slice()))
+                )
+            ));
+        }
+
+        for ((source, directory), allowed_secrets) in candidates.into_iter().zip(allowed_secrets) {
             report!(sender, "adding file '{}'", source.display());
 
-            let source_path = std::fs::canonicalize(source)?;
+            let link = self.link && source.is_symlink();
+
+            let source_path = match link {
+                true => file::canonicalize_path(&source)?,
+                false => std::fs::canonicalize(source)?,
+            };
 
             tracing::trace!(source=?source_path, "canonicalized source path");
 
             if metadata.file_is_already_managed(&source_path) {
                 tracing::trace!("file is already managed, skipping");
-                return Ok(());
+                continue;
+            }
+
+            if link {
+                let destination_path = paths.repo_local_file_path(&source_path)?;
+                let mut file_data = FileData::link(source_path, destination_path)?;
+                file_data.conditions = conditions.clone();
+                metadata.manage_file(file_data);
+                continue;
             }
 
-            let destination_path = paths.repo_local_file_path(&source_path)?;
+            let mut file_data = match directory {
+                Some(index) => directories[index].file_data(source_path)?,
+                None => {
+                    let destination_path = paths.repo_local_file_path(&source_path)?;
+                    let mut file_data = FileData::new(
+                        source_path,
+                        destination_path,
+                        encrypt,
+                        self.key_group.clone(),
+                    );
+                    if self.armor {
+                        file_data.armor = Some(true);
+                    }
+                    file_data.backend = self.backend.clone();
+                    file_data.mode = self.mode;
+                    file_data.template = self.template;
+                    file_data
+                }
+            };
+
+            if private {
+                file_data.repo_path = paths
+                    .private_repo_file_path(&file::opaque_file_name(&file_data.system_path, key)?);
+                file_data.private = true;
+            }
+            file_data.allowed_secrets = allowed_secrets;
+            file_data.conditions = conditions.clone();
 
-            let file_data = FileData::new(source_path, destination_path, self.encrypt);
+            if let Some(parent) = file_data.repo_path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
 
-            file::copy_from_system(&file_data, &config.encryption.passphrase)?;
+            file::copy_from_system(&mut file_data, &config.encryption, &vault)?;
 
             metadata.manage_file(file_data);
         }
 
+        for directory in directories.into_iter() {
+            metadata.manage_directory(directory);
+        }
+
+        if encrypt && self.backend.is_none() {
+            metadata.ensure_key_check(self.key_group.as_deref(), key)?;
+        }
+
         metadata.persist()?;
         file::write_cache(&metadata, &paths.metadata_cache)?;
 
@@ -56,3 +238,14 @@ impl Runnable for AddOp {
         Ok(())
     }
 }
+
+impl AddOp {
+    /// the machines the added file(s) are managed on
+    fn conditions(&self) -> Conditions {
+        Conditions {
+            hosts: self.hosts.clone(),
+            os: self.os.clone(),
+            requires_command: self.requires_command.clone(),
+        }
+    }
+}
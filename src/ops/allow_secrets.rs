@@ -0,0 +1,57 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use crossbeam_channel::Sender;
+
+use crate::{
+    config::Config,
+    file::{self, Metadata},
+    paths::Paths,
+    report, scan,
+    vault::Vault,
+};
+
+use super::{Message, Runnable};
+
+pub struct AllowSecretsOp {
+    pub files: Vec<PathBuf>,
+}
+
+impl Runnable for AllowSecretsOp {
+    fn run(&self, config: Config, paths: Paths, sender: Option<Sender<Message>>) -> Result<()> {
+        if self.files.is_empty() {
+            report!(sender, "No file(s) specified!");
+            return Ok(());
+        }
+
+        let mut metadata = Metadata::read_unsealed(&paths.metadata, &config.encryption)?;
+        let vault = Vault::open(&paths.vault, &config.encryption);
+
+        let files = file::canonicalize_paths(&self.files);
+
+        for file in files.iter() {
+            let Some(file_data) = metadata.get_file_data_by_system_path_mut(file) else {
+                report!(sender, "'{}' is not managed, skipping", file.display());
+                continue;
+            };
+
+            let findings = scan::scan_redacted(file, &file_data.allowed_secrets, &vault)?;
+
+            if findings.is_empty() {
+                report!(sender, "no new potential secrets in '{}'", file.display());
+                continue;
+            }
+
+            for finding in findings.into_iter() {
+                report!(sender, "allowing {} in '{}'", finding, file.display());
+                file_data.allowed_secrets.push(finding.fingerprint);
+            }
+        }
+
+        metadata.persist()?;
+        file::write_cache(&metadata, &paths.metadata_cache)?;
+
+        report!(sender, "done!");
+        Ok(())
+    }
+}
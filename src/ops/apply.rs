@@ -10,6 +10,7 @@ use crate::{
     git::Repo,
     paths::Paths,
     report,
+    vault::Vault,
 };
 
 use super::{Message, Runnable};
@@ -20,6 +21,10 @@ pub struct ApplyOp {
 }
 
 impl Runnable for ApplyOp {
+    fn touches_encrypted_files(&self) -> bool {
+        true
+    }
+
     fn run(&self, config: Config, paths: Paths, sender: Option<Sender<Message>>) -> Result<()> {
         let repo = Repo::open(&paths)?;
 
@@ -28,7 +33,8 @@ impl Runnable for ApplyOp {
             return Ok(());
         }
 
-        let mut metadata = Metadata::read(&paths.metadata)?;
+        let mut metadata = Metadata::read_unsealed(&paths.metadata, &config.encryption)?;
+        let vault = Vault::open(&paths.vault, &config.encryption);
 
         let maybe_files = file::canonicalize_optional_paths(self.files.as_ref());
 
@@ -36,10 +42,21 @@ impl Runnable for ApplyOp {
             report!(sender, "preparing selected files");
             metadata
                 .files
-                .retain(|file| files.contains(&file.system_path));
+                .retain(|file| files.iter().any(|path| file.system_path.starts_with(path)));
         }
 
+        metadata.files.retain(|file| file.applies_to_this_machine());
+
         for file_data in metadata.files.iter() {
+            if file_data.encrypted && !file_data.key_is_available(&config.encryption) {
+                report!(
+                    sender,
+                    "skipping '{}', its key is not available on this machine",
+                    file_data.system_path.display()
+                );
+                continue;
+            }
+
             if !self.no_confirm {
                 let prompt = format!("Do you want to apply '{}'", file_data.system_path.display());
 
@@ -60,7 +77,15 @@ impl Runnable for ApplyOp {
                 }
             }
 
-            file::copy_from_repo(file_data, &config.encryption.passphrase)?;
+            if file_data.encrypted && file::is_readable_by_others(&file_data.system_path) {
+                report!(
+                    sender,
+                    "warning: '{}' is encrypted in the repo but other users can read it on this machine, restricting it to owner-only access",
+                    file_data.system_path.display()
+                );
+            }
+
+            file::apply_from_repo(file_data, &config, &vault)?;
         }
 
         report!(sender, "done!");
@@ -22,7 +22,7 @@ impl Runnable for CheckoutOp {
 
         report!(sender, "checked out '{}'", &config.upstream.branch);
 
-        config.write()?;
+        config.write(&paths.config)?;
 
         report!(sender, "done!");
 
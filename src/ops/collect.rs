@@ -1,14 +1,18 @@
 use std::path::PathBuf;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use crossbeam_channel::Sender;
 use dialoguer::{theme::ColorfulTheme, Confirm};
+use zeroize::Zeroizing;
 
 use crate::{
     config::Config,
-    file::{self, Metadata},
+    directory,
+    file::{self, FileData, Metadata},
+    merge,
     paths::Paths,
-    report,
+    report, scan,
+    vault::Vault,
 };
 
 use super::{Message, Runnable};
@@ -19,28 +23,121 @@ pub struct CollectOp {
 }
 
 impl Runnable for CollectOp {
+    fn touches_encrypted_files(&self) -> bool {
+        true
+    }
+
     fn run(&self, config: Config, paths: Paths, sender: Option<Sender<Message>>) -> Result<()> {
-        let mut metadata = Metadata::read(&paths.metadata)?;
+        let mut metadata = Metadata::read_unsealed(&paths.metadata, &config.encryption)?;
+        let vault = Vault::open(&paths.vault, &config.encryption);
 
         let maybe_files = file::canonicalize_optional_paths(self.files.as_ref());
 
+        let mut blocked = vec![];
+
+        if self.collect_directories(
+            &mut metadata,
+            maybe_files.as_deref(),
+            &config,
+            &paths,
+            &vault,
+            &mut blocked,
+            &sender,
+        )? | self.collect_rule_matches(
+            &mut metadata,
+            maybe_files.as_deref(),
+            &config,
+            &paths,
+            &vault,
+            &mut blocked,
+            &sender,
+        )? {
+            metadata.persist()?;
+            file::write_cache(&metadata, &paths.metadata_cache)?;
+        }
+
         if let Some(files) = maybe_files {
             report!(sender, "preparing selected files");
             metadata
                 .files
-                .retain(|file| files.contains(&file.system_path));
+                .retain(|file| files.iter().any(|path| file.system_path.starts_with(path)));
         }
 
-        for file in metadata.files.iter() {
+        metadata.files.retain(|file| file.applies_to_this_machine());
+
+        let mut collected_any = false;
+
+        for file in metadata.files.iter_mut() {
+            if file.encrypted && !file.key_is_available(&config.encryption) {
+                report!(
+                    sender,
+                    "skipping '{}', its key is not available on this machine",
+                    file.system_path.display()
+                );
+                continue;
+            }
+
+            // `exists` follows symlinks, a managed symlink may point nowhere on this machine
+            if !file.system_path.exists() && !file.system_path.is_symlink() {
+                report!(
+                    sender,
+                    "skipping '{}', it does not exist on this machine",
+                    file.system_path.display()
+                );
+                continue;
+            }
+
+            // the system copy of a template is rendered, copying it would replace the template
+            if file.template {
+                collected_any |=
+                    self.collect_template(file, &config, &vault, &mut blocked, &sender)?;
+                continue;
+            }
+
+            // edits to files applied as symlinks already are edits to the repo copy
+            if file::is_linked_to_repo(file) {
+                tracing::trace!(file = ?file.system_path, "file is linked to the repo, skipping");
+                continue;
+            }
+
             report!(sender, "collecting file '{}'", file.system_path.display());
 
-            if !file::source_was_updated(&file.system_path, &file.repo_path)? {
+            if !file::system_file_was_updated(file, &config.encryption, &vault)? {
                 tracing::trace!("source has not been updated since last time");
+
+                // a changed mode alone needs no confirmation, the contents stay the same
+                if file.permissions_changed()? {
+                    report!(
+                        sender,
+                        "collecting changed permissions of '{}'",
+                        file.system_path.display()
+                    );
+                    file.permissions = file::file_permissions(&file.system_path)?;
+                    collected_any = true;
+                }
                 continue;
             }
 
+            if !file.encrypted && !file.is_link() {
+                let findings =
+                    scan::scan_redacted(&file.system_path, &file.allowed_secrets, &vault)?;
+
+                if !findings.is_empty() {
+                    report!(
+                        sender,
+                        scan::describe_findings([(
+                            file.system_path.as_path(),
+                            findings.as_slice()
+                        )])
+                    );
+                    blocked.push(file.system_path.clone());
+                    continue;
+                }
+            }
+
             if self.no_confirm {
-                file::copy_from_system(file, &config.encryption.passphrase)?;
+                file::copy_from_system(file, &config.encryption, &vault)?;
+                collected_any = true;
                 continue;
             }
 
@@ -51,11 +148,355 @@ impl Runnable for CollectOp {
 
             tracing::trace!("user gave confirmation: {confirmation}");
             if confirmation {
-                file::copy_from_system(file, &config.encryption.passphrase)?;
+                file::copy_from_system(file, &config.encryption, &vault)?;
+                collected_any = true;
+            }
+        }
+
+        // only the selected files are left when filtering, so reread the metadata before
+        // recording the updated plaintext hashes
+        if collected_any {
+            let mut full_metadata = Metadata::read_unsealed(&paths.metadata, &config.encryption)?;
+            for file in metadata.files.into_iter() {
+                if let Some(file_data) =
+                    full_metadata.get_file_data_by_system_path_mut(&file.system_path)
+                {
+                    *file_data = file;
+                }
             }
+            full_metadata.persist()?;
+            file::write_cache(&full_metadata, &paths.metadata_cache)?;
+        }
+
+        if !blocked.is_empty() {
+            return Err(anyhow!(
+                "{} file(s) were not collected because they look like they contain secrets. Use `conman encrypt` to store them encrypted, or `conman allow-secrets` if they are safe to store in plaintext",
+                blocked.len()
+            ));
         }
 
         report!(sender, "done!");
         Ok(())
     }
 }
+
+impl CollectOp {
+    /// manage new files in tracked directories and stop managing the ones that were deleted,
+    /// returning whether anything changed
+    #[allow(clippy::too_many_arguments)]
+    fn collect_directories(
+        &self,
+        metadata: &mut Metadata,
+        selected: Option<&[PathBuf]>,
+        config: &Config,
+        paths: &Paths,
+        vault: &Vault,
+        blocked: &mut Vec<PathBuf>,
+        sender: &Option<Sender<Message>>,
+    ) -> Result<bool> {
+        let mut changed = false;
+
+        for directory in metadata.directories.clone().into_iter() {
+            if selected.is_some_and(|files| {
+                !files
+                    .iter()
+                    .any(|path| directory.contains(path) || directory.system_path.starts_with(path))
+            }) {
+                continue;
+            }
+
+            // files of directories for other machines are neither added nor removed here
+            if !directory.conditions.apply_to_this_machine()
+                || !directory.key_is_available(&config.encryption)
+            {
+                continue;
+            }
+
+            // a missing directory most likely has not been applied yet rather than been deleted
+            if !directory.system_path.is_dir() {
+                report!(
+                    sender,
+                    "skipping '{}', it does not exist on this machine",
+                    directory.system_path.display()
+                );
+                continue;
+            }
+
+            let system_files = directory::walk(&directory.system_path)?;
+
+            for system_path in system_files.iter() {
+                if metadata.file_is_already_managed(system_path) {
+                    continue;
+                }
+
+                let file_data = directory.file_data(system_path.clone())?;
+                changed |=
+                    self.add_new_file(metadata, file_data, config, paths, vault, blocked, sender)?;
+            }
+
+            let deleted: Vec<_> = metadata
+                .files
+                .iter()
+                .filter(|file| {
+                    // files for other machines are missing here without having been deleted
+                    file.applies_to_this_machine()
+                        && directory.contains(&file.system_path)
+                        && !system_files.contains(&file.system_path)
+                })
+                .map(|file| file.system_path.clone())
+                .collect();
+
+            for system_path in deleted.into_iter() {
+                // deleted or newly ignored files are no longer part of the directory
+                if !self.confirm(&format!("Stop managing '{}'?", system_path.display()))? {
+                    continue;
+                }
+
+                report!(sender, "removing file '{}'", system_path.display());
+
+                if let Some(file_data) = metadata.unmanage_file(&system_path)? {
+                    file::remove_from_repo(&file_data)?;
+                }
+                changed = true;
+            }
+        }
+
+        Ok(changed)
+    }
+
+    /// offer the unmanaged files matching a tracking rule for management, returning whether any
+    /// were added
+    #[allow(clippy::too_many_arguments)]
+    fn collect_rule_matches(
+        &self,
+        metadata: &mut Metadata,
+        selected: Option<&[PathBuf]>,
+        config: &Config,
+        paths: &Paths,
+        vault: &Vault,
+        blocked: &mut Vec<PathBuf>,
+        sender: &Option<Sender<Message>>,
+    ) -> Result<bool> {
+        let mut new_files = vec![];
+
+        for (rule, system_path) in metadata.untracked_rule_matches()?.into_iter() {
+            if selected.is_some_and(|files| !files.iter().any(|path| system_path.starts_with(path)))
+            {
+                continue;
+            }
+
+            if rule.encrypt
+                && !config
+                    .encryption
+                    .key_is_available(rule.key_group.as_deref())
+            {
+                report!(
+                    sender,
+                    "skipping '{}', its key is not available on this machine",
+                    system_path.display()
+                );
+                continue;
+            }
+
+            let mut file_data = FileData::new(
+                system_path.clone(),
+                paths.repo_local_file_path(&system_path)?,
+                rule.encrypt,
+                rule.key_group.clone(),
+            );
+            file_data.conditions = rule.conditions.clone();
+            file_data.private = rule.private;
+
+            new_files.push(file_data);
+        }
+
+        let mut changed = false;
+
+        for file_data in new_files.into_iter() {
+            changed |=
+                self.add_new_file(metadata, file_data, config, paths, vault, blocked, sender)?;
+        }
+
+        Ok(changed)
+    }
+
+    /// merge the edits made to the rendered system copy of a template back into the template,
+    /// returning whether the template changed
+    ///
+    /// edits to templated lines can not be merged automatically, they are resolved in the editor
+    /// unless confirmations are skipped, in which case the file is skipped
+    fn collect_template(
+        &self,
+        file: &mut FileData,
+        config: &Config,
+        vault: &Vault,
+        blocked: &mut Vec<PathBuf>,
+        sender: &Option<Sender<Message>>,
+    ) -> Result<bool> {
+        let rendered = file::render_repo_file(file, config, vault)?;
+        let system = Zeroizing::new(std::fs::read_to_string(&file.system_path)?);
+
+        if *rendered == *system {
+            tracing::trace!("rendered template matches the system copy");
+            return Ok(false);
+        }
+
+        if !file.encrypted {
+            let findings = scan::scan_redacted(&file.system_path, &file.allowed_secrets, vault)?;
+
+            if !findings.is_empty() {
+                report!(
+                    sender,
+                    scan::describe_findings([(file.system_path.as_path(), findings.as_slice())])
+                );
+                blocked.push(file.system_path.clone());
+                return Ok(false);
+            }
+        }
+
+        report!(
+            sender,
+            "collecting template '{}'",
+            file.system_path.display()
+        );
+
+        let source = file::read_template(file, &config.encryption)?;
+        let merge = merge::merge(&source, &rendered, &system);
+        let conflicts = merge.conflicts;
+        let mut template = Zeroizing::new(merge.template);
+
+        if conflicts > 0 {
+            if self.no_confirm {
+                report!(
+                    sender,
+                    "skipping '{}', its changes touch templated lines, run `conman collect` without `--no-confirm` to merge them",
+                    file.system_path.display()
+                );
+                return Ok(false);
+            }
+
+            report!(
+                sender,
+                "{} change(s) to '{}' touch templated lines, resolve them in the editor",
+                conflicts,
+                file.system_path.display()
+            );
+
+            loop {
+                template = Zeroizing::new(edit::edit(template.as_bytes())?);
+
+                if !merge::has_conflict_markers(&template) {
+                    break;
+                }
+
+                if !self.confirm("The template still has conflict markers, edit it again?")? {
+                    return Ok(false);
+                }
+            }
+        } else if !self.confirm(&format!(
+            "Collect updated template '{}'?",
+            file.system_path.display()
+        ))? {
+            return Ok(false);
+        }
+
+        // secrets typed into the system copy are stored as placeholders
+        if !file.encrypted {
+            template = Zeroizing::new(vault.redact(&template)?);
+        }
+
+        file::write_template(file, &config.encryption, &template)?;
+
+        if *file::render_repo_file(file, config, vault)? != *system {
+            report!(
+                sender,
+                "the updated template of '{}' renders differently than the system copy, check it with `conman render`",
+                file.system_path.display()
+            );
+        }
+
+        Ok(true)
+    }
+
+    /// scan, confirm and copy a file that is not managed yet, returning whether it was added
+    ///
+    /// like `add` does, private files get an opaque repo path and the key files encrypted with age
+    /// are encrypted with is recorded for later verification
+    #[allow(clippy::too_many_arguments)]
+    fn add_new_file(
+        &self,
+        metadata: &mut Metadata,
+        mut file_data: FileData,
+        config: &Config,
+        paths: &Paths,
+        vault: &Vault,
+        blocked: &mut Vec<PathBuf>,
+        sender: &Option<Sender<Message>>,
+    ) -> Result<bool> {
+        let system_path = file_data.system_path.clone();
+
+        if !file_data.encrypted {
+            let findings = scan::scan_redacted(&system_path, &[], vault)?;
+
+            if !findings.is_empty() {
+                report!(
+                    sender,
+                    scan::describe_findings([(system_path.as_path(), findings.as_slice())])
+                );
+                blocked.push(system_path);
+                return Ok(false);
+            }
+        }
+
+        if !self.confirm(&format!("Add new file '{}'?", system_path.display()))? {
+            return Ok(false);
+        }
+
+        report!(sender, "adding new file '{}'", system_path.display());
+
+        let key = match file_data.encrypted && file_data.backend.is_none() {
+            true => Some(config.encryption.key(file_data.key_group.as_deref())?),
+            false => None,
+        };
+
+        // private like the sealed directory or rule that found it, even if `privacy` was turned
+        // off since, a readable repo path next to the sealed entry would reveal it
+        if file_data.private {
+            let Some(key) = key else {
+                return Err(anyhow!(
+                    "'{}' is private but not encrypted with age",
+                    system_path.display()
+                ));
+            };
+            file_data.repo_path =
+                paths.private_repo_file_path(&file::opaque_file_name(&system_path, key)?);
+        }
+
+        if let Some(parent) = file_data.repo_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        file::copy_from_system(&mut file_data, &config.encryption, vault)?;
+
+        let key_group = file_data.key_group.clone();
+        metadata.manage_file(file_data);
+
+        if let Some(key) = key {
+            metadata.ensure_key_check(key_group.as_deref(), key)?;
+        }
+
+        Ok(true)
+    }
+
+    fn confirm(&self, prompt: &str) -> Result<bool> {
+        if self.no_confirm {
+            return Ok(true);
+        }
+
+        let confirmation = Confirm::with_theme(&ColorfulTheme::default())
+            .with_prompt(prompt)
+            .interact()?;
+
+        tracing::trace!("user gave confirmation: {confirmation}");
+        Ok(confirmation)
+    }
+}
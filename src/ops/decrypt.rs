@@ -0,0 +1,96 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use crossbeam_channel::Sender;
+use dialoguer::{theme::ColorfulTheme, Confirm};
+
+use crate::{
+    config::Config,
+    file::{self, Metadata},
+    paths::Paths,
+    report,
+};
+
+use super::{Message, Runnable};
+
+pub struct DecryptOp {
+    pub files: Vec<PathBuf>,
+    pub no_confirm: bool,
+}
+
+impl Runnable for DecryptOp {
+    fn touches_encrypted_files(&self) -> bool {
+        true
+    }
+
+    fn run(&self, config: Config, paths: Paths, sender: Option<Sender<Message>>) -> Result<()> {
+        if self.files.is_empty() {
+            report!(sender, "No file(s) specified!");
+            return Ok(());
+        }
+
+        let mut metadata = Metadata::read_unsealed(&paths.metadata, &config.encryption)?;
+
+        let files = file::canonicalize_paths(&self.files);
+
+        for file in files.iter() {
+            let Some(file_data) = metadata.get_file_data_by_system_path_mut(file) else {
+                report!(sender, "'{}' is not managed, skipping", file.display());
+                continue;
+            };
+
+            if !file_data.encrypted {
+                report!(sender, "'{}' is not encrypted, skipping", file.display());
+                continue;
+            }
+
+            if !self.no_confirm {
+                let prompt = format!(
+                    "'{}' will be stored in plaintext from now on, are you sure?",
+                    file.display()
+                );
+
+                let confirmation = Confirm::with_theme(&ColorfulTheme::default())
+                    .with_prompt(prompt)
+                    .default(false)
+                    .interact()?;
+
+                if !confirmation {
+                    report!(sender, "keeping '{}' encrypted", file.display());
+                    continue;
+                }
+            }
+
+            report!(sender, "decrypting file '{}'", file.display());
+
+            let contents = file::decrypt_repo_file(file_data, &config.encryption)?;
+
+            // a plaintext file must not keep an opaque name that suggests it is private
+            if file_data.private {
+                let public_path = paths.repo_local_file_path(&file_data.system_path)?;
+                if let Some(parent) = public_path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                std::fs::write(&public_path, contents)?;
+                file::remove_from_repo(file_data)?;
+
+                file_data.repo_path = public_path;
+                file_data.private = false;
+            } else {
+                std::fs::write(&file_data.repo_path, contents)?;
+            }
+
+            file_data.encrypted = false;
+            file_data.key_group = None;
+            file_data.backend = None;
+            file_data.armor = None;
+            file_data.plaintext_hash = None;
+        }
+
+        metadata.persist()?;
+        file::write_cache(&metadata, &paths.metadata_cache)?;
+
+        report!(sender, "done!");
+        Ok(())
+    }
+}
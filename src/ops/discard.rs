@@ -10,6 +10,7 @@ use crate::{
     git::{Repo, StatusType},
     paths::Paths,
     report,
+    vault::Vault,
 };
 
 use super::{Message, Runnable};
@@ -20,10 +21,15 @@ pub struct DiscardOp {
 }
 
 impl Runnable for DiscardOp {
+    fn touches_encrypted_files(&self) -> bool {
+        true
+    }
+
     fn run(&self, config: Config, paths: Paths, sender: Option<Sender<Message>>) -> Result<()> {
         let repo = Repo::open(&paths)?;
 
-        let mut metadata = Metadata::read(&paths.metadata)?;
+        let mut metadata = Metadata::read_unsealed(&paths.metadata, &config.encryption)?;
+        let vault = Vault::open(&paths.vault, &config.encryption);
 
         let mut status_changes = match repo.status_changes() {
             Ok(Some(status_changes)) => status_changes,
@@ -42,7 +48,7 @@ impl Runnable for DiscardOp {
             report!(sender, "preparing selected files");
             status_changes.retain(|change| {
                 let Some(file_data) =
-                    metadata.get_file_data_where_repo_path_ends_with(&change.relative_path)
+                    metadata.get_file_data_by_relative_repo_path(&change.relative_path)
                 else {
                     return false;
                 };
@@ -52,12 +58,11 @@ impl Runnable for DiscardOp {
 
         let files_to_reset: Vec<_> = status_changes
             .into_iter()
-            .map(|change| {
+            .filter_map(|change| {
                 metadata
-                    .get_file_data_where_repo_path_ends_with(&change.relative_path)
+                    .get_file_data_by_relative_repo_path(&change.relative_path)
                     .map(|file| (change, file.clone()))
             })
-            .flatten()
             .filter(|(_, file)| {
                 if self.no_confirm {
                     return true;
@@ -89,7 +94,7 @@ impl Runnable for DiscardOp {
                     should_persist_metadata = true;
                 }
                 StatusType::Modified => {
-                    file::copy_from_repo(&file, &config.encryption.passphrase)?;
+                    file::apply_from_repo(&file, &config, &vault)?;
                 }
                 StatusType::Deleted => {
                     metadata.manage_file(file);
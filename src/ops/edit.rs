@@ -3,12 +3,14 @@ use std::path::PathBuf;
 use anyhow::Result;
 use crossbeam_channel::Sender;
 use dialoguer::{theme::ColorfulTheme, FuzzySelect};
+use zeroize::Zeroizing;
 
 use crate::{
     config::Config,
     file::{self, Metadata},
     paths::Paths,
     report,
+    vault::Vault,
 };
 
 use super::{Message, Runnable};
@@ -19,14 +21,19 @@ pub struct EditOp {
 }
 
 impl Runnable for EditOp {
+    fn touches_encrypted_files(&self) -> bool {
+        true
+    }
+
     fn run(&self, config: Config, paths: Paths, sender: Option<Sender<Message>>) -> Result<()> {
-        let metadata = Metadata::read(&paths.metadata)?;
+        let mut metadata = Metadata::read_unsealed(&paths.metadata, &config.encryption)?;
+        let vault = Vault::open(&paths.vault, &config.encryption);
 
         let maybe_file_data = match &self.path {
             Some(path) => {
                 tracing::trace!("user specified path: {}", path.display());
 
-                let path = std::fs::canonicalize(path)?;
+                let path = file::canonicalize_path(path)?;
                 tracing::trace!("canonicalized path: {}", path.display());
 
                 metadata.get_file_data_by_system_path(&path)
@@ -47,13 +54,38 @@ impl Runnable for EditOp {
             }
         };
 
-        let Some(file_data) = maybe_file_data else {
+        let Some(system_path) = maybe_file_data.map(|file_data| file_data.system_path.clone())
+        else {
             tracing::trace!("no file data found, exiting");
             return Ok(());
         };
 
+        let file_data = metadata
+            .get_file_data_by_system_path_mut(&system_path)
+            .unwrap();
+
         report!(sender, "editing '{}'", &file_data.system_path.display());
 
+        // the system copy of a template is rendered, so the template itself is edited instead
+        if file_data.template {
+            let source = file::read_template(file_data, &config.encryption)?;
+            let edited = Zeroizing::new(edit::edit(source.as_bytes())?);
+
+            if *edited == *source {
+                report!(sender, "no changes made, nothing to do");
+                return Ok(());
+            }
+
+            file::write_template(file_data, &config.encryption, &edited)?;
+            file::apply_from_repo(file_data, &config, &vault)?;
+
+            metadata.persist()?;
+            file::write_cache(&metadata, &paths.metadata_cache)?;
+
+            report!(sender, "done!");
+            return Ok(());
+        }
+
         edit::edit_file(&file_data.system_path)?;
 
         report!(sender, "handling edited file");
@@ -64,7 +96,7 @@ impl Runnable for EditOp {
         }
 
         let source_was_updated =
-            file::source_was_updated(&file_data.system_path, &file_data.repo_path)?;
+            file::system_file_was_updated(file_data, &config.encryption, &vault)?;
         tracing::Span::current().record("source_was_updated", source_was_updated);
 
         if !source_was_updated {
@@ -72,7 +104,11 @@ impl Runnable for EditOp {
             return Ok(());
         }
 
-        file::copy_from_system(file_data, &config.encryption.passphrase)?;
+        file::copy_from_system(file_data, &config.encryption, &vault)?;
+
+        metadata.persist()?;
+        file::write_cache(&metadata, &paths.metadata_cache)?;
+
         report!(sender, "done!");
 
         Ok(())
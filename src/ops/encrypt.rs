@@ -0,0 +1,108 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use crossbeam_channel::Sender;
+use zeroize::Zeroizing;
+
+use crate::{
+    config::Config,
+    file::{self, Metadata},
+    paths::Paths,
+    report,
+};
+
+use super::{Message, Runnable};
+
+pub struct EncryptOp {
+    pub files: Vec<PathBuf>,
+    pub key_group: Option<String>,
+    pub armor: bool,
+    pub backend: Option<String>,
+}
+
+impl Runnable for EncryptOp {
+    fn touches_encrypted_files(&self) -> bool {
+        true
+    }
+
+    fn run(&self, config: Config, paths: Paths, sender: Option<Sender<Message>>) -> Result<()> {
+        if self.files.is_empty() {
+            report!(sender, "No file(s) specified!");
+            return Ok(());
+        }
+
+        let key = config.encryption.key(self.key_group.as_deref())?;
+
+        let mut metadata = Metadata::read_unsealed(&paths.metadata, &config.encryption)?;
+
+        let files = file::canonicalize_paths(&self.files);
+
+        for file in files.iter() {
+            let Some(file_data) = metadata.get_file_data_by_system_path_mut(file) else {
+                report!(sender, "'{}' is not managed, skipping", file.display());
+                continue;
+            };
+
+            if file_data.encrypted {
+                report!(
+                    sender,
+                    "'{}' is already encrypted, skipping",
+                    file.display()
+                );
+                continue;
+            }
+
+            if file_data.is_link() {
+                report!(
+                    sender,
+                    "'{}' is managed as a symlink, skipping",
+                    file.display()
+                );
+                continue;
+            }
+
+            report!(sender, "encrypting file '{}'", file.display());
+
+            // a symlink would point at the ciphertext from now on
+            file::unlink_from_repo(file_data)?;
+
+            // encrypt the repo copy rather than the system copy to not sneak in uncollected changes
+            let contents = Zeroizing::new(std::fs::read(&file_data.repo_path)?);
+            if self.armor {
+                file_data.armor = Some(true);
+            }
+            file_data.key_group = self.key_group.clone();
+            file_data.backend = self.backend.clone();
+
+            // only files encrypted with age have a key to derive opaque names from
+            let private = config.encryption.privacy && self.backend.is_none();
+
+            if private {
+                let private_path = paths
+                    .private_repo_file_path(&file::opaque_file_name(&file_data.system_path, key)?);
+                file_data.plaintext_hash =
+                    file::encrypt_into(file_data, &config.encryption, &contents, &private_path)?;
+                file::remove_from_repo(file_data)?;
+
+                file_data.repo_path = private_path;
+                file_data.private = true;
+            } else {
+                let repo_path = file_data.repo_path.clone();
+                file_data.plaintext_hash =
+                    file::encrypt_into(file_data, &config.encryption, &contents, &repo_path)?;
+            }
+
+            file_data.encrypted = true;
+        }
+
+        if self.backend.is_none() {
+            metadata.ensure_key_check(self.key_group.as_deref(), key)?;
+        }
+
+        metadata.persist()?;
+        file::write_cache(&metadata, &paths.metadata_cache)?;
+
+        report!(sender, "done!");
+        Ok(())
+    }
+}
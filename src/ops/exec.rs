@@ -0,0 +1,166 @@
+use std::{fmt::Display, path::PathBuf, process::Command};
+
+use anyhow::{anyhow, Result};
+use crossbeam_channel::Sender;
+use zeroize::Zeroizing;
+
+use crate::{
+    config::Config,
+    file::{self, FileData, Metadata},
+    paths::Paths,
+};
+
+use super::{Message, Runnable};
+
+/// The exit code of a child process that did not exit successfully, forwarded by `main`
+#[derive(Debug)]
+pub struct ExitCode(pub i32);
+
+impl Display for ExitCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "command exited with code {}", self.0)
+    }
+}
+
+impl std::error::Error for ExitCode {}
+
+pub struct ExecOp {
+    /// name or path of a managed encrypted env file
+    pub env: String,
+    pub command: Vec<String>,
+}
+
+impl Runnable for ExecOp {
+    fn touches_encrypted_files(&self) -> bool {
+        true
+    }
+
+    fn run(&self, config: Config, paths: Paths, _sender: Option<Sender<Message>>) -> Result<()> {
+        let Some((program, args)) = self.command.split_first() else {
+            return Err(anyhow!("no command specified"));
+        };
+
+        let metadata = Metadata::read_unsealed(&paths.metadata, &config.encryption)?;
+        let file_data = find_env_file(&metadata, &self.env)?;
+
+        // the plaintext only ever lives in memory and is zeroed once the child exited
+        let contents = file::decrypt_repo_file(file_data, &config.encryption)?;
+        let variables = parse_env(std::str::from_utf8(&contents)?)?;
+
+        tracing::trace!(count = variables.len(), "injecting environment variables");
+
+        let status = Command::new(program)
+            .args(args)
+            .envs(variables.iter().map(|(name, value)| (name, value.as_str())))
+            .status()
+            .map_err(|e| anyhow!("failed to run '{program}': {e}"))?;
+
+        if status.success() {
+            return Ok(());
+        }
+
+        Err(ExitCode(exit_code(status)).into())
+    }
+}
+
+/// find the encrypted file given by `env`, either by its system path or by its file name with or
+/// without extension, e.g. `work` for `~/.config/env/work.env`
+fn find_env_file<'a>(metadata: &'a Metadata, env: &str) -> Result<&'a FileData> {
+    if let Ok(path) = std::fs::canonicalize(PathBuf::from(env)) {
+        if let Some(file_data) = metadata.get_file_data_by_system_path(&path) {
+            return encrypted_env_file(file_data);
+        }
+    }
+
+    let matches: Vec<_> = metadata
+        .files
+        .iter()
+        .filter(|file_data| file_data.encrypted)
+        .filter(|file_data| {
+            let path = &file_data.system_path;
+            path.file_name().is_some_and(|name| name == env)
+                || path.file_stem().is_some_and(|stem| stem == env)
+        })
+        .collect();
+
+    match matches.as_slice() {
+        [file_data] => Ok(file_data),
+        [] => Err(anyhow!("no managed encrypted env file named '{env}'")),
+        _ => Err(anyhow!(
+            "'{env}' matches several encrypted files, use its path instead:\n{}",
+            matches
+                .iter()
+                .map(|file_data| file_data.system_path.display().to_string())
+                .collect::<Vec<_>>()
+                .join("\n")
+        )),
+    }
+}
+
+fn encrypted_env_file(file_data: &FileData) -> Result<&FileData> {
+    if !file_data.encrypted {
+        return Err(anyhow!(
+            "'{}' is not encrypted, use `conman encrypt` first",
+            file_data.system_path.display()
+        ));
+    }
+    Ok(file_data)
+}
+
+/// parse `KEY=VALUE` lines, ignoring blank lines, comments and a leading `export`
+fn parse_env(contents: &str) -> Result<Vec<(String, Zeroizing<String>)>> {
+    let mut variables = vec![];
+
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let line = line.strip_prefix("export ").unwrap_or(line);
+
+        let Some((name, value)) = line.split_once('=') else {
+            return Err(anyhow!("line {} of the env file is not `KEY=VALUE`", i + 1));
+        };
+
+        let name = name.trim();
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(anyhow!(
+                "line {} of the env file has an invalid variable name",
+                i + 1
+            ));
+        }
+
+        let value = value.trim();
+        let value = ['"', '\'']
+            .into_iter()
+            .find_map(|quote| {
+                value
+                    .strip_prefix(quote)
+                    .and_then(|value| value.strip_suffix(quote))
+            })
+            .unwrap_or(value);
+
+        variables.push((name.to_string(), Zeroizing::new(value.to_string())));
+    }
+
+    Ok(variables)
+}
+
+/// the exit code of a child, or 128 plus the signal that terminated it like a shell would report
+fn exit_code(status: std::process::ExitStatus) -> i32 {
+    if let Some(code) = status.code() {
+        return code;
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+
+        if let Some(signal) = status.signal() {
+            return 128 + signal;
+        }
+    }
+
+    1
+}
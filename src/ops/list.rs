@@ -8,8 +8,8 @@ use super::Runnable;
 pub struct ListOp;
 
 impl Runnable for ListOp {
-    fn run(&self, _config: Config, paths: Paths, sender: Option<Sender<Message>>) -> Result<()> {
-        let metadata = Metadata::read(&paths.metadata)?;
+    fn run(&self, config: Config, paths: Paths, sender: Option<Sender<Message>>) -> Result<()> {
+        let metadata = Metadata::read_unsealed(&paths.metadata, &config.encryption)?;
 
         let encrypted_count = metadata.files.iter().filter(|file| file.encrypted).count();
         let non_encrypted_count = metadata.files.len() - encrypted_count;
@@ -31,6 +31,14 @@ impl Runnable for ListOp {
             }
         }
 
+        if !metadata.rules.is_empty() {
+            report!(sender, "tracking rules:");
+            for rule in metadata.rules.iter() {
+                let encrypted = if rule.encrypt { " (encrypted)" } else { "" };
+                report!(sender, "{}{}", rule.pattern.display(), encrypted);
+            }
+        }
+
         Ok(())
     }
 }
@@ -0,0 +1,183 @@
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Context, Result};
+use crossbeam_channel::Sender;
+
+use crate::{
+    config::Config,
+    file::{self, Metadata},
+    git::Repo,
+    paths::Paths,
+    report,
+    vault::Vault,
+};
+
+use super::{Message, Runnable};
+
+/// Moves files stored under the old flat `{timestamp}-{file_name}` names to the layout mirroring
+/// the system, see `Paths::repo_local_file_path`, and records the move in a single commit
+pub struct MigrateLayoutOp;
+
+impl Runnable for MigrateLayoutOp {
+    fn run(&self, config: Config, paths: Paths, sender: Option<Sender<Message>>) -> Result<()> {
+        let repo = Repo::open(&paths)?;
+
+        if repo.check_has_unsaved()? {
+            report!(sender, "save or discard unsaved changes first");
+            return Ok(());
+        }
+
+        let mut metadata = Metadata::read_unsealed(&paths.metadata, &config.encryption)?;
+        let vault = Vault::open(&paths.vault, &config.encryption);
+
+        // private files keep their opaque names, their repo paths must not reveal anything
+        let mut moves: Vec<(usize, PathBuf)> = vec![];
+        for (index, file_data) in metadata.files.iter().enumerate() {
+            if file_data.private {
+                continue;
+            }
+
+            let repo_path = paths.repo_local_file_path(&file_data.system_path)?;
+            if repo_path == file_data.repo_path {
+                continue;
+            }
+
+            // managed symlinks have no repo copy to move
+            if !file_data.is_link() && !file_data.repo_path.exists() {
+                return Err(anyhow!(
+                    "the repo copy of '{}' is missing at '{}'",
+                    file_data.system_path.display(),
+                    file_data.repo_path.display()
+                ));
+            }
+
+            if repo_path.exists() {
+                return Err(anyhow!(
+                    "can not move '{}', '{}' already exists",
+                    file_data.system_path.display(),
+                    repo_path.display()
+                ));
+            }
+
+            moves.push((index, repo_path));
+        }
+
+        let mut directories_changed = false;
+        for directory in metadata
+            .directories
+            .iter_mut()
+            .filter(|directory| !directory.private)
+        {
+            let repo_path = paths.repo_local_file_path(&directory.system_path)?;
+            if repo_path != directory.repo_path {
+                directory.repo_path = repo_path;
+                directories_changed = true;
+            }
+        }
+
+        if moves.is_empty() && !directories_changed {
+            report!(sender, "the repo already uses the current layout");
+            return Ok(());
+        }
+
+        let file_count = moves.len();
+
+        // files are moved one by one, everything moved so far is moved back if a later move or
+        // persisting the metadata fails so the repo is left in its old layout
+        let mut moved = Vec::with_capacity(file_count);
+        let result = moves
+            .into_iter()
+            .try_for_each(|(index, repo_path)| {
+                let file_data = &mut metadata.files[index];
+
+                // files applied as symlinks would be left pointing at the old repo path
+                let linked = file::is_linked_to_repo(file_data);
+
+                if !file_data.is_link() {
+                    if let Some(parent) = repo_path.parent() {
+                        std::fs::create_dir_all(parent)?;
+                    }
+
+                    std::fs::rename(&file_data.repo_path, &repo_path).with_context(|| {
+                        format!(
+                            "failed to move '{}' to '{}'",
+                            file_data.repo_path.display(),
+                            repo_path.display()
+                        )
+                    })?;
+                }
+
+                tracing::trace!(from=?file_data.repo_path, to=?repo_path, "moved repo file");
+                let previous = std::mem::replace(&mut file_data.repo_path, repo_path);
+                moved.push(Move {
+                    index,
+                    previous,
+                    linked,
+                });
+
+                if linked {
+                    file::link_from_repo(file_data, &config.encryption, &vault)?;
+                }
+
+                report!(sender, "moved '{}'", file_data.system_path.display());
+                Ok(())
+            })
+            .and_then(|_| metadata.persist());
+
+        if let Err(error) = result {
+            roll_back(&mut metadata, moved, &config, &vault);
+            return Err(error.context("migration failed, moved files were moved back"));
+        }
+
+        file::write_cache(&metadata, &paths.metadata_cache)?;
+
+        repo.commit_changes(format!(
+            "system-migrate: moved {file_count} file(s) to the readable repo layout"
+        ))?;
+
+        report!(
+            sender,
+            "done! run `conman apply` on your other machines after pulling to update files applied as symlinks"
+        );
+        Ok(())
+    }
+}
+
+/// A repo file that was moved to the new layout
+struct Move {
+    index: usize,
+    previous: PathBuf,
+    linked: bool,
+}
+
+/// move files back to where they were before the migration, in reverse order
+///
+/// this is best effort, failures are logged and the remaining files are still moved back
+fn roll_back(metadata: &mut Metadata, moved: Vec<Move>, config: &Config, vault: &Vault) {
+    for moved_file in moved.into_iter().rev() {
+        let file_data = &mut metadata.files[moved_file.index];
+
+        if !file_data.is_link() {
+            if let Err(error) = std::fs::rename(&file_data.repo_path, &moved_file.previous) {
+                tracing::warn!(
+                    "failed to move '{}' back to '{}': {error}",
+                    file_data.repo_path.display(),
+                    moved_file.previous.display()
+                );
+                continue;
+            }
+        }
+
+        tracing::trace!(to=?moved_file.previous, "moved repo file back");
+        file_data.repo_path = moved_file.previous;
+
+        if moved_file.linked {
+            if let Err(error) = file::link_from_repo(file_data, &config.encryption, vault) {
+                tracing::warn!(
+                    "failed to link '{}' again: {error}",
+                    file_data.system_path.display()
+                );
+            }
+        }
+    }
+}
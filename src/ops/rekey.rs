@@ -0,0 +1,201 @@
+use std::{io::Write, path::PathBuf};
+
+use anyhow::{Context, Result};
+use crossbeam_channel::Sender;
+use dialoguer::{theme::ColorfulTheme, Password};
+
+use crate::{
+    config::{Config, KeyConfig},
+    file::{self, Metadata},
+    git::Repo,
+    paths::Paths,
+    report,
+    vault::Vault,
+};
+
+use super::{Message, Runnable};
+
+pub struct RekeyOp {
+    pub passphrase: bool,
+    pub recipients: Vec<String>,
+    pub identity_file: Option<PathBuf>,
+    pub key_group: Option<String>,
+}
+
+impl Runnable for RekeyOp {
+    fn touches_encrypted_files(&self) -> bool {
+        true
+    }
+
+    fn run(&self, mut config: Config, paths: Paths, sender: Option<Sender<Message>>) -> Result<()> {
+        if !self.passphrase && self.recipients.is_empty() && self.identity_file.is_none() {
+            report!(
+                sender,
+                "nothing to do, specify a new passphrase, recipient(s) or identity file"
+            );
+            return Ok(());
+        }
+
+        let repo = Repo::open(&paths)?;
+
+        if repo.check_has_unsaved()? {
+            report!(sender, "save or discard unsaved changes first");
+            return Ok(());
+        }
+
+        let current_key = config.encryption.key(self.key_group.as_deref())?;
+
+        let mut metadata = Metadata::read_unsealed(&paths.metadata, &config.encryption)?;
+
+        let encrypted_files: Vec<_> = metadata
+            .files
+            .iter()
+            .filter(|file| {
+                file.encrypted && file.backend.is_none() && file.key_group == self.key_group
+            })
+            .collect();
+
+        report!(
+            sender,
+            "decrypting {} file(s) with the current key",
+            encrypted_files.len()
+        );
+
+        // decrypt everything up front so that a single failure aborts before anything is written
+        let mut decrypted_files = Vec::with_capacity(encrypted_files.len());
+        for file_data in encrypted_files.into_iter() {
+            let contents =
+                file::decrypt_repo_file(file_data, &config.encryption).with_context(|| {
+                    format!(
+                        "failed to decrypt '{}', nothing was changed",
+                        file_data.system_path.display()
+                    )
+                })?;
+            decrypted_files.push((file_data, contents));
+        }
+
+        // the vault is encrypted with the default key
+        let vault = Vault::open(&paths.vault, &config.encryption);
+        let rekey_vault = self.key_group.is_none() && vault.exists();
+        if rekey_vault {
+            vault
+                .names()
+                .context("failed to decrypt the vault, nothing was changed")?;
+        }
+
+        // the hash key is kept across key changes, it has to be decrypted with the current key
+        file::resolve_hash_key(current_key)?;
+
+        let key = self.rekeyed_key(current_key)?;
+
+        report!(sender, "re-encrypting file(s) with the new key");
+
+        // plaintext hashes stay valid, the hash key is only sealed with the new key
+        let mut reencrypted_files = Vec::with_capacity(decrypted_files.len());
+        for (file_data, contents) in decrypted_files.into_iter() {
+            let contents =
+                file::encrypt_contents(&contents, &key, file_data.armored(&config.encryption))?;
+            reencrypted_files.push((file_data.repo_path.clone(), contents));
+        }
+
+        // e.g. new recipients the configured identity file can not decrypt would lock the user out,
+        // check with an empty payload if there are no files to re-encrypt
+        let probe = match reencrypted_files.first() {
+            Some((_, contents)) => contents.clone(),
+            None => {
+                file::encrypt_contents(&[], &key, false).context("the new key can not be used")?
+            }
+        };
+        file::decrypt_contents(&probe, &key)
+            .context("the new key can not decrypt what it encrypts, nothing was changed")?;
+
+        let file_count = reencrypted_files.len();
+
+        let passphrase_source_is_external = self.passphrase && key.passphrase.is_none();
+        let passphrase_env_var = key.passphrase_env_var();
+
+        match self.key_group.as_ref() {
+            Some(key_group) => {
+                config.encryption.key_groups.insert(key_group.clone(), key);
+            }
+            None => config.encryption.default_key = key,
+        }
+
+        // everything is written next to its destination first and then replaced all or nothing,
+        // so a failure leaves the files, the vault and the config as they were
+        let mut staged_files = Vec::with_capacity(file_count + 2);
+        for (repo_path, contents) in reencrypted_files.iter() {
+            staged_files.push(file::stage_file(repo_path, None, |file| {
+                Ok(file.write_all(contents)?)
+            })?);
+        }
+        if rekey_vault {
+            staged_files.push(vault.stage(config.encryption.key(None)?)?);
+        }
+        staged_files.push(config.stage(&paths.config)?);
+
+        file::commit_all(staged_files)?;
+        tracing::trace!("wrote re-encrypted files");
+        if rekey_vault {
+            report!(sender, "re-encrypted the vault");
+        }
+        report!(sender, "updated config");
+
+        // private entries are sealed with the key as well
+        metadata.reseal(self.key_group.as_deref(), &config.encryption)?;
+        metadata.replace_key_check(
+            self.key_group.as_deref(),
+            config.encryption.key(self.key_group.as_deref())?,
+        )?;
+        metadata.persist()?;
+        file::write_cache(&metadata, &paths.metadata_cache)?;
+
+        if repo.check_has_unsaved()? {
+            repo.commit_changes(format!("system-rekey: re-encrypted {file_count} file(s)"))?;
+        }
+
+        if passphrase_source_is_external {
+            report!(
+                sender,
+                "remember to update `passphrase_command` or ${} to the new passphrase",
+                passphrase_env_var
+            );
+        }
+
+        report!(sender, "done!");
+        Ok(())
+    }
+}
+
+impl RekeyOp {
+    /// build the key that files are re-encrypted with
+    fn rekeyed_key(&self, current: &KeyConfig) -> Result<KeyConfig> {
+        let mut key = current.clone();
+
+        if self.passphrase {
+            let passphrase = Password::with_theme(&ColorfulTheme::default())
+                .with_prompt("New encryption passphrase")
+                .with_confirmation("Repeat the new passphrase", "passphrases do not match")
+                .interact()?;
+
+            // new files are only encrypted with the passphrase if no public keys are configured
+            key.recipients.clear();
+            key.identity_file = None;
+            key.set_passphrase(passphrase.into());
+
+            return Ok(key);
+        }
+
+        if let Some(identity_file) = self.identity_file.as_ref() {
+            key.identity_file = Some(std::fs::canonicalize(identity_file)?);
+            // derive the recipients from the new identity file unless given explicitly
+            key.recipients.clear();
+        }
+
+        if !self.recipients.is_empty() {
+            key.recipients = self.recipients.clone();
+        }
+
+        Ok(key)
+    }
+}
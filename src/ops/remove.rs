@@ -17,23 +17,37 @@ pub struct RemoveOp {
 }
 
 impl Runnable for RemoveOp {
-    fn run(&self, _config: Config, paths: Paths, sender: Option<Sender<Message>>) -> Result<()> {
+    fn run(&self, config: Config, paths: Paths, sender: Option<Sender<Message>>) -> Result<()> {
         if self.files.is_empty() {
             report!(sender, "No file(s) specified!");
             return Ok(());
         }
 
-        let mut metadata = Metadata::read(&paths.metadata)?;
+        let mut metadata = Metadata::read_unsealed(&paths.metadata, &config.encryption)?;
 
         let files = file::canonicalize_paths(&self.files);
 
         for file in files {
+            if let Some((directory, files)) = metadata.unmanage_directory(&file) {
+                report!(sender, "removing directory '{}'", file.display());
+
+                for file_data in files.iter() {
+                    file::unlink_from_repo(file_data)?;
+                    file::remove_from_repo(file_data)?;
+                }
+                if directory.repo_path.exists() {
+                    std::fs::remove_dir_all(&directory.repo_path)?;
+                }
+                continue;
+            }
+
             report!(sender, "removing file '{}'", file.display());
 
             let Some(file_data) = metadata.unmanage_file(&file)? else {
                 return Ok(());
             };
 
+            file::unlink_from_repo(&file_data)?;
             file::remove_from_repo(&file_data)?;
         }
 
@@ -0,0 +1,45 @@
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Result};
+use crossbeam_channel::Sender;
+
+use crate::{
+    config::Config,
+    file::{self, Metadata},
+    paths::Paths,
+    report,
+    vault::Vault,
+};
+
+use super::{Message, Runnable};
+
+/// Prints a managed template the way `apply` would render it, without writing anything
+pub struct RenderOp {
+    pub file: PathBuf,
+}
+
+impl Runnable for RenderOp {
+    fn touches_encrypted_files(&self) -> bool {
+        true
+    }
+
+    fn run(&self, config: Config, paths: Paths, sender: Option<Sender<Message>>) -> Result<()> {
+        let metadata = Metadata::read_unsealed(&paths.metadata, &config.encryption)?;
+        let vault = Vault::open(&paths.vault, &config.encryption);
+
+        let system_path = file::canonicalize_path(&self.file)?;
+
+        let Some(file_data) = metadata.get_file_data_by_system_path(&system_path) else {
+            return Err(anyhow!("'{}' is not managed", system_path.display()));
+        };
+
+        if !file_data.template {
+            return Err(anyhow!("'{}' is not a template", system_path.display()));
+        }
+
+        let rendered = file::render_repo_file(file_data, &config, &vault)?;
+        report!(sender, rendered.to_string());
+
+        Ok(())
+    }
+}
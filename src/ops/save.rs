@@ -1,12 +1,12 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use crossbeam_channel::Sender;
 
 use crate::{
     config::Config,
     file::{self, Metadata},
-    git::{Repo, StatusChange},
-    paths::Paths,
-    report,
+    git::{Repo, StatusChange, StatusType},
+    paths::{Paths, PRIVATE_FILE_PREFIX, VAULT_FILE_NAME},
+    report, scan,
 };
 
 use super::{Message, Runnable};
@@ -30,6 +30,40 @@ impl Runnable for SaveOp {
 
         let metadata = Metadata::read(&paths.metadata)?;
 
+        // last line of defence against committing plaintext secrets
+        let mut findings = vec![];
+        for change in status_changes.iter() {
+            if change.status == StatusType::Deleted {
+                continue;
+            }
+
+            let Some(file_data) =
+                metadata.get_file_data_by_relative_repo_path(&change.relative_path)
+            else {
+                continue;
+            };
+
+            if file_data.encrypted {
+                continue;
+            }
+
+            let file_findings = scan::scan_file(&file_data.repo_path, &file_data.allowed_secrets)?;
+            if !file_findings.is_empty() {
+                findings.push((file_data.system_path.as_path(), file_findings));
+            }
+        }
+
+        if !findings.is_empty() {
+            return Err(anyhow!(
+                "{}\nuse `conman encrypt` to store the file(s) encrypted, or `conman allow-secrets` if they are safe to store in plaintext",
+                scan::describe_findings(
+                    findings
+                        .iter()
+                        .map(|(path, findings)| (*path, findings.as_slice()))
+                )
+            ));
+        }
+
         let commit_message = construct_commit_message(&metadata, status_changes);
         repo.commit_changes(commit_message)?;
 
@@ -46,17 +80,31 @@ fn construct_commit_message(metadata: &Metadata, status_changes: Vec<StatusChang
     let change_count = status_changes.len();
 
     for (i, change) in status_changes.into_iter().enumerate() {
-        let maybe_file_data =
-            metadata.get_file_data_where_repo_path_ends_with(&change.relative_path);
+        // the system paths of private files never end up in the history
+        let is_private = change
+            .relative_path
+            .to_string_lossy()
+            .starts_with(PRIVATE_FILE_PREFIX);
+
+        let path = if is_private {
+            "private file".to_string()
+        } else if change.relative_path.ends_with(VAULT_FILE_NAME) {
+            "secrets".to_string()
+        } else {
+            let maybe_file_data =
+                metadata.get_file_data_by_relative_repo_path(&change.relative_path);
+
+            let Some(file_data) = maybe_file_data else {
+                continue;
+            };
 
-        let Some(file_data) = maybe_file_data else {
-            continue;
+            file_data.system_path.display().to_string()
         };
 
         let update = format!(
             "{}: {}{}",
             change.status.to_str(),
-            file_data.system_path.display(),
+            path,
             if i + 1 == change_count { "" } else { "\n" }
         );
 
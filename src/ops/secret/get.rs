@@ -0,0 +1,29 @@
+use anyhow::{anyhow, Result};
+use crossbeam_channel::Sender;
+
+use crate::{
+    config::Config,
+    ops::{Message, Runnable},
+    paths::Paths,
+    report,
+    vault::Vault,
+};
+
+pub struct GetOp(pub String);
+
+impl Runnable for GetOp {
+    fn touches_encrypted_files(&self) -> bool {
+        true
+    }
+
+    fn run(&self, config: Config, paths: Paths, sender: Option<Sender<Message>>) -> Result<()> {
+        let vault = Vault::open(&paths.vault, &config.encryption);
+
+        let Some(value) = vault.get(&self.0)? else {
+            return Err(anyhow!("the secret '{}' is not in the vault", self.0));
+        };
+
+        report!(sender, value.to_string());
+        Ok(())
+    }
+}
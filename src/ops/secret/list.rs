@@ -0,0 +1,35 @@
+use anyhow::Result;
+use crossbeam_channel::Sender;
+
+use crate::{
+    config::Config,
+    ops::{Message, Runnable},
+    paths::Paths,
+    report,
+    vault::Vault,
+};
+
+pub struct ListOp;
+
+impl Runnable for ListOp {
+    fn touches_encrypted_files(&self) -> bool {
+        true
+    }
+
+    fn run(&self, config: Config, paths: Paths, sender: Option<Sender<Message>>) -> Result<()> {
+        let vault = Vault::open(&paths.vault, &config.encryption);
+
+        let names = vault.names()?;
+
+        if names.is_empty() {
+            report!(sender, "no secrets stored");
+            return Ok(());
+        }
+
+        for name in names.into_iter() {
+            report!(sender, name.to_string());
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,9 @@
+pub mod get;
+pub mod list;
+pub mod remove;
+pub mod set;
+
+pub use get::GetOp;
+pub use list::ListOp;
+pub use remove::RemoveOp;
+pub use set::SetOp;
@@ -0,0 +1,32 @@
+use anyhow::Result;
+use crossbeam_channel::Sender;
+
+use crate::{
+    config::Config,
+    ops::{Message, Runnable},
+    paths::Paths,
+    report,
+    vault::Vault,
+};
+
+pub struct RemoveOp(pub String);
+
+impl Runnable for RemoveOp {
+    fn touches_encrypted_files(&self) -> bool {
+        true
+    }
+
+    fn run(&self, config: Config, paths: Paths, sender: Option<Sender<Message>>) -> Result<()> {
+        let mut vault = Vault::open(&paths.vault, &config.encryption);
+
+        if !vault.remove(&self.0)? {
+            report!(sender, "no secret named '{}'", self.0);
+            return Ok(());
+        }
+
+        vault.write()?;
+
+        report!(sender, "removed secret '{}'", self.0);
+        Ok(())
+    }
+}
@@ -0,0 +1,48 @@
+use anyhow::Result;
+use crossbeam_channel::Sender;
+use dialoguer::{theme::ColorfulTheme, Password};
+
+use crate::{
+    config::Config,
+    file::{self, Metadata},
+    ops::{Message, Runnable},
+    paths::Paths,
+    report,
+    vault::Vault,
+};
+
+pub struct SetOp {
+    pub name: String,
+    pub value: Option<String>,
+}
+
+impl Runnable for SetOp {
+    fn touches_encrypted_files(&self) -> bool {
+        true
+    }
+
+    fn run(&self, config: Config, paths: Paths, sender: Option<Sender<Message>>) -> Result<()> {
+        let mut vault = Vault::open(&paths.vault, &config.encryption);
+
+        let value = match &self.value {
+            Some(value) => value.clone(),
+            None => Password::with_theme(&ColorfulTheme::default())
+                .with_prompt(format!("Value of secret '{}'", self.name))
+                .interact()?,
+        };
+
+        vault.set(&self.name, value)?;
+        vault.write()?;
+
+        // the vault is encrypted with the default key, which has to match on every machine
+        let mut metadata = Metadata::read_unsealed(&paths.metadata, &config.encryption)?;
+        if !metadata.has_key_check(None) {
+            metadata.ensure_key_check(None, config.encryption.key(None)?)?;
+            metadata.persist()?;
+            file::write_cache(&metadata, &paths.metadata_cache)?;
+        }
+
+        report!(sender, "stored secret '{}'", self.name);
+        Ok(())
+    }
+}
@@ -1,36 +1,123 @@
+use std::path::Path;
+
 use anyhow::Result;
 use crossbeam_channel::Sender;
 
-use crate::{config::Config, git::Repo, paths::Paths, report};
+use crate::{
+    config::Config,
+    file::{self, Metadata},
+    git::Repo,
+    paths::Paths,
+    report,
+};
 
 use super::{Message, Runnable};
 
 pub struct StatusOp;
 
 impl Runnable for StatusOp {
-    fn run(&self, _config: Config, paths: Paths, sender: Option<Sender<Message>>) -> Result<()> {
+    fn run(&self, config: Config, paths: Paths, sender: Option<Sender<Message>>) -> Result<()> {
         let repo = Repo::open(&paths)?;
 
-        let status_changes = match repo.status_changes() {
-            Ok(Some(status_changes)) => status_changes,
-            Ok(None) => {
-                report!(sender, "no changes found");
-                return Ok(());
+        let status_changes = repo.status_changes()?;
+
+        let metadata = Metadata::read_unsealed(&paths.metadata, &config.encryption)?;
+
+        // tracking rules are re-evaluated every time, their matches are not part of the repo yet
+        let untracked = metadata.untracked_rule_matches()?;
+
+        let mut mode_changes = vec![];
+        for file_data in metadata.files.iter() {
+            if file_data.applies_to_this_machine() && file_data.permissions_changed()? {
+                mode_changes.push((
+                    file_data,
+                    file::file_permissions(&file_data.system_path)?.unwrap_or_default(),
+                ));
             }
-            Err(e) => {
-                return Err(e)?;
+        }
+
+        let target_changes: Vec<_> = metadata
+            .files
+            .iter()
+            .filter(|file_data| file_data.applies_to_this_machine())
+            .filter_map(|file_data| {
+                file_data
+                    .changed_link_target()
+                    .map(|target| (file_data, target))
+            })
+            .collect();
+
+        if status_changes.is_none()
+            && untracked.is_empty()
+            && mode_changes.is_empty()
+            && target_changes.is_empty()
+        {
+            report!(sender, "no changes found");
+            return Ok(());
+        }
+
+        if let Some(status_changes) = status_changes {
+            report!(sender, "unsaved changes:");
+
+            for change in status_changes.iter() {
+                // private files have opaque repo names, show their system path instead
+                let path = match metadata.get_file_data_by_relative_repo_path(&change.relative_path)
+                {
+                    Some(file_data) if file_data.private => file_data.system_path.clone(),
+                    _ => change.relative_path.clone(),
+                };
+
+                report!(sender, "{}: {}", change.status.to_str(), path.display())
             }
-        };
+        }
 
-        report!(sender, "unsaved changes:");
+        if !mode_changes.is_empty() {
+            report!(
+                sender,
+                "changed permissions, use `conman collect` to record them:"
+            );
+
+            for (file_data, permissions) in mode_changes.iter() {
+                report!(
+                    sender,
+                    "mode: {} ({:04o} -> {:04o})",
+                    file_data.system_path.display(),
+                    file_data.permissions.unwrap_or_default(),
+                    permissions
+                )
+            }
+        }
+
+        if !target_changes.is_empty() {
+            report!(
+                sender,
+                "changed symlink targets, use `conman collect` to record them:"
+            );
+
+            for (file_data, target) in target_changes.iter() {
+                report!(
+                    sender,
+                    "target: {} ({} -> {})",
+                    file_data.system_path.display(),
+                    file_data
+                        .link_target
+                        .as_deref()
+                        .unwrap_or(Path::new(""))
+                        .display(),
+                    target.display()
+                )
+            }
+        }
 
-        for change in status_changes.iter() {
+        if !untracked.is_empty() {
             report!(
                 sender,
-                "{}: {}",
-                change.status.to_str(),
-                change.relative_path.display()
-            )
+                "new files matching tracking rules, use `conman collect` to add them:"
+            );
+
+            for (_, path) in untracked.iter() {
+                report!(sender, "new: {}", path.display())
+            }
         }
 
         Ok(())
@@ -0,0 +1,99 @@
+use anyhow::{anyhow, Result};
+use crossbeam_channel::Sender;
+
+use crate::{
+    config::Config,
+    directory,
+    file::{self, Metadata, TrackingRule},
+    machine::Conditions,
+    paths::Paths,
+    report,
+};
+
+use super::{Message, Runnable};
+
+#[derive(Default)]
+pub struct TrackOp {
+    pub pattern: String,
+    pub encrypt: bool,
+    pub key_group: Option<String>,
+    pub excludes: Vec<String>,
+    /// only manage matching files on these machines, see `FileData::applies_to_this_machine`
+    pub hosts: Vec<String>,
+    /// only manage matching files on this operating system
+    pub os: Option<String>,
+    /// only manage matching files on machines where this command is installed
+    pub requires_command: Option<String>,
+}
+
+impl Runnable for TrackOp {
+    fn run(&self, config: Config, paths: Paths, sender: Option<Sender<Message>>) -> Result<()> {
+        let encrypt = self.encrypt || self.key_group.is_some();
+
+        // fail early on unknown key groups
+        if encrypt {
+            config.encryption.key(self.key_group.as_deref())?;
+        }
+
+        let conditions = Conditions {
+            hosts: self.hosts.clone(),
+            os: self.os.clone(),
+            requires_command: self.requires_command.clone(),
+        };
+        conditions.validate()?;
+
+        let pattern = TrackingRule::normalize_pattern(&self.pattern)?;
+
+        // a literal pattern only ever matches itself, never the files inside a directory
+        if directory::is_literal(&pattern) && pattern.is_dir() {
+            return Err(anyhow!(
+                "'{}' is a directory, track '{}' to match the files inside it or add it with `conman add`",
+                pattern.display(),
+                pattern.join("**").display()
+            ));
+        }
+
+        // the rule would reveal the paths of the private files it adds, see `FileData::private`
+        let private = encrypt && config.encryption.privacy;
+
+        let rule = TrackingRule {
+            pattern,
+            encrypt,
+            key_group: self.key_group.clone(),
+            excludes: self.excludes.clone(),
+            conditions,
+            private,
+        };
+
+        // also validates the pattern and the excludes before the rule is stored
+        let matches = rule.matching_files()?;
+
+        let mut metadata = Metadata::read_unsealed(&paths.metadata, &config.encryption)?;
+
+        if metadata
+            .rules
+            .iter()
+            .any(|other| other.pattern == rule.pattern)
+        {
+            report!(
+                sender,
+                "'{}' is already tracked, skipping",
+                rule.pattern.display()
+            );
+            return Ok(());
+        }
+
+        report!(sender, "tracking '{}'", rule.pattern.display());
+        metadata.rules.push(rule);
+
+        metadata.persist()?;
+        file::write_cache(&metadata, &paths.metadata_cache)?;
+
+        report!(
+            sender,
+            "{} file(s) currently match, use `conman collect` to add them",
+            matches.len()
+        );
+        Ok(())
+    }
+}
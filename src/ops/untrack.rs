@@ -0,0 +1,42 @@
+use anyhow::Result;
+use crossbeam_channel::Sender;
+
+use crate::{
+    config::Config,
+    file::{self, Metadata, TrackingRule},
+    paths::Paths,
+    report,
+};
+
+use super::{Message, Runnable};
+
+/// Removes a tracking rule, the files it matched stay managed
+pub struct UntrackOp {
+    pub pattern: String,
+}
+
+impl Runnable for UntrackOp {
+    fn run(&self, config: Config, paths: Paths, sender: Option<Sender<Message>>) -> Result<()> {
+        let pattern = TrackingRule::normalize_pattern(&self.pattern)?;
+
+        let mut metadata = Metadata::read_unsealed(&paths.metadata, &config.encryption)?;
+
+        let count = metadata.rules.len();
+        metadata.rules.retain(|rule| rule.pattern != pattern);
+
+        if metadata.rules.len() == count {
+            report!(sender, "'{}' is not tracked, skipping", pattern.display());
+            return Ok(());
+        }
+
+        metadata.persist()?;
+        file::write_cache(&metadata, &paths.metadata_cache)?;
+
+        report!(
+            sender,
+            "stopped tracking '{}', files it matched are still managed",
+            pattern.display()
+        );
+        Ok(())
+    }
+}
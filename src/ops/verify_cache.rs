@@ -8,6 +8,7 @@ use crate::{
     git::Repo,
     paths::Paths,
     report,
+    vault::Vault,
 };
 
 use super::{Message, Runnable};
@@ -27,7 +28,8 @@ impl Runnable for VerifyCacheOp {
 
         let mut metadata = Metadata::read(&paths.metadata)?;
 
-        let cache_verdict = file::verify_cache(&paths.metadata, &paths.metadata_cache)?;
+        let cache_verdict =
+            file::verify_cache(&paths.metadata, &paths.metadata_cache, &config.encryption)?;
 
         tracing::trace!("got cache verdict: {cache_verdict:?}");
 
@@ -36,14 +38,17 @@ impl Runnable for VerifyCacheOp {
                 file::write_cache(&metadata, &paths.metadata_cache)?
             }
             CacheVerdict::HandleDangling(dangling) => {
+                metadata.unseal(&config.encryption)?;
+
                 report!(
                     sender,
                     "detected differences in managed files since last run!"
                 );
 
                 let file_options = ["skip", "delete", "manage"];
+                let vault = Vault::open(&paths.vault, &config.encryption);
 
-                for file in dangling.into_iter() {
+                for mut file in dangling.into_iter() {
                     let choice = dialoguer::Select::with_theme(&ColorfulTheme::default())
                         .with_prompt(format!(
                             "Handle dangling file {}",
@@ -59,7 +64,7 @@ impl Runnable for VerifyCacheOp {
                             report!(sender, "deleted file");
                         }
                         "manage" => {
-                            file::copy_from_system(&file, &config.encryption.passphrase)?;
+                            file::copy_from_system(&mut file, &config.encryption, &vault)?;
                             metadata.manage_file(file);
                             report!(sender, "managed file");
                         }
@@ -0,0 +1,82 @@
+use anyhow::{anyhow, Result};
+use crossbeam_channel::Sender;
+
+use crate::{
+    config::Config,
+    file::{self, Metadata},
+    paths::Paths,
+    report,
+};
+
+use super::{Message, Runnable};
+
+pub struct VerifySecretsOp;
+
+impl Runnable for VerifySecretsOp {
+    fn run(&self, config: Config, paths: Paths, sender: Option<Sender<Message>>) -> Result<()> {
+        let mut metadata = Metadata::read(&paths.metadata)?;
+
+        match metadata.verify_keys(&config.encryption) {
+            Ok(()) => report!(sender, "configured key(s) match the repo"),
+            Err(e) => report!(sender, e.to_string()),
+        }
+
+        metadata.unseal(&config.encryption)?;
+
+        let mut failed = vec![];
+        let mut verified_key_groups = vec![];
+
+        for file_data in metadata.files.iter().filter(|file| file.encrypted) {
+            if !file_data.key_is_available(&config.encryption) {
+                report!(
+                    sender,
+                    "skipping '{}', its key is not available on this machine",
+                    file_data.system_path.display()
+                );
+                continue;
+            }
+
+            match file::verify_repo_file(file_data, &config.encryption) {
+                Ok(()) => {
+                    report!(sender, "ok: {}", file_data.system_path.display());
+                    if file_data.backend.is_none() {
+                        verified_key_groups.push(file_data.key_group.clone());
+                    }
+                }
+                Err(e) => {
+                    report!(sender, "failed: {} ({e})", file_data.system_path.display());
+                    failed.push(file_data.key_group.clone());
+                }
+            }
+        }
+
+        // repos encrypted before key checks existed get one for every key that decrypted all of
+        // its files
+        let mut recorded_key_check = false;
+        for key_group in verified_key_groups.iter() {
+            if failed.contains(key_group) || metadata.has_key_check(key_group.as_deref()) {
+                continue;
+            }
+
+            let key = config.encryption.key(key_group.as_deref())?;
+            metadata.ensure_key_check(key_group.as_deref(), key)?;
+            recorded_key_check = true;
+        }
+
+        if recorded_key_check {
+            metadata.persist()?;
+            file::write_cache(&metadata, &paths.metadata_cache)?;
+            report!(sender, "recorded missing key check(s)");
+        }
+
+        if !failed.is_empty() {
+            return Err(anyhow!(
+                "{} encrypted file(s) could not be decrypted",
+                failed.len()
+            ));
+        }
+
+        report!(sender, "done!");
+        Ok(())
+    }
+}
@@ -1,23 +1,29 @@
 use std::{
     fs,
-    path::PathBuf,
-    time::{SystemTime, UNIX_EPOCH},
+    path::{Component, Path, PathBuf},
 };
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use directories::BaseDirs;
 use tracing::instrument;
 
 pub(crate) const APPLICATION_NAME: &str = "conman";
+pub(crate) const CONFIG_FILE_NAME: &str = "config.toml";
 pub(crate) const METADATA_FILE_NAME: &str = "_conman_internal_metadata.toml";
 pub(crate) const METADATA_CACHE_FILE_NAME: &str = "_metadata_cache.toml";
 pub(crate) const REPO_DIRECTORY: &str = "_conman_repo";
+pub(crate) const PRIVATE_FILE_PREFIX: &str = "private-";
+pub(crate) const HOME_DIRECTORY: &str = "home";
+pub(crate) const SYSTEM_DIRECTORY: &str = "system";
+pub(crate) const VAULT_FILE_NAME: &str = "_conman_secrets.age";
 
 #[derive(Clone)]
 pub struct Paths {
+    pub config: PathBuf,
     pub repo: PathBuf,
     pub metadata: PathBuf,
     pub metadata_cache: PathBuf,
+    pub vault: PathBuf,
 }
 
 impl Paths {
@@ -34,24 +40,53 @@ impl Paths {
         let metadata_cache = cache.join(METADATA_CACHE_FILE_NAME);
 
         let metadata = repo.join(METADATA_FILE_NAME);
+        let vault = repo.join(VAULT_FILE_NAME);
+
+        let config = base_dirs
+            .config_dir()
+            .join(APPLICATION_NAME)
+            .join(CONFIG_FILE_NAME);
 
         Ok(Self {
+            config,
             repo,
             metadata,
             metadata_cache,
+            vault,
         })
     }
 
-    pub fn repo_local_file_path(&self, on_disk_path: &PathBuf) -> Result<PathBuf> {
-        let file_name = on_disk_path.file_name().unwrap().to_string_lossy();
-
-        let start = SystemTime::now();
-        let timestamp = start.duration_since(UNIX_EPOCH)?.as_secs();
+    /// the repo path of a file, mirroring where it is on the system so the repo can be browsed,
+    /// e.g. `home/.config/nvim/init.lua` for `~/.config/nvim/init.lua` and `system/etc/hosts` for
+    /// `/etc/hosts`
+    pub fn repo_local_file_path(&self, on_disk_path: &Path) -> Result<PathBuf> {
+        if !on_disk_path.is_absolute() {
+            return Err(anyhow!(
+                "'{}' has to be an absolute path",
+                on_disk_path.display()
+            ));
+        }
+
+        // SEE: `Paths::new` on why this can not fail
+        let base_dirs = BaseDirs::new().unwrap();
 
-        let name = format!("{timestamp}-{file_name}");
+        let relative_path = match on_disk_path.strip_prefix(base_dirs.home_dir()) {
+            Ok(home_relative_path) => Path::new(HOME_DIRECTORY).join(home_relative_path),
+            Err(_) => Path::new(SYSTEM_DIRECTORY).join(
+                on_disk_path
+                    .components()
+                    .filter(|component| matches!(component, Component::Normal(_)))
+                    .collect::<PathBuf>(),
+            ),
+        };
+
+        Ok(self.repo.join(relative_path))
+    }
 
-        let path = self.repo.join(name);
-        Ok(path)
+    /// the repo path of a private file, which reveals nothing about its system path
+    pub fn private_repo_file_path(&self, opaque_name: &str) -> PathBuf {
+        self.repo
+            .join(format!("{PRIVATE_FILE_PREFIX}{opaque_name}"))
     }
 }
 
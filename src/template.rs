@@ -0,0 +1,50 @@
+use std::collections::BTreeMap;
+
+use anyhow::{Context, Result};
+use minijinja::{context, Environment, UndefinedBehavior, Value};
+use tracing::instrument;
+use zeroize::Zeroizing;
+
+use crate::{
+    config::Config,
+    machine,
+    vault::{self, Vault},
+};
+
+/// render the contents of a template, e.g. `email = {{ data.email }}`, for this machine
+///
+/// templates use the jinja syntax and can refer to:
+/// - `hostname`, `os` (e.g. `linux` or `macos`) and `username` of this machine
+/// - `data`, the `[data]` table of the config
+/// - `secret.NAME`, the secrets of the vault, which is only decrypted if the template uses it
+///
+/// undefined variables are an error rather than rendering as nothing
+#[instrument(skip_all)]
+pub fn render(contents: &str, config: &Config, vault: &Vault) -> Result<Zeroizing<String>> {
+    let mut environment = Environment::new();
+    environment.set_undefined_behavior(UndefinedBehavior::Strict);
+    // config files commonly end with a newline, which jinja would strip otherwise
+    environment.set_keep_trailing_newline(true);
+
+    let mut secrets = BTreeMap::new();
+    if vault::has_placeholders(contents) {
+        for name in vault.names()? {
+            secrets.insert(name, vault.get(name)?.unwrap_or_default());
+        }
+    }
+
+    let variables = context! {
+        hostname => machine::hostname(),
+        os => machine::os(),
+        username => whoami::username(),
+        data => Value::from_serialize(&config.data),
+        secret => secrets,
+    };
+
+    let rendered = environment
+        .render_str(contents, variables)
+        .context("failed to render template")?;
+
+    tracing::trace!("rendered template");
+    Ok(Zeroizing::new(rendered))
+}
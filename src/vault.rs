@@ -0,0 +1,225 @@
+use std::{
+    cell::OnceCell,
+    collections::BTreeMap,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+use zeroize::Zeroizing;
+
+use crate::{
+    config::{EncryptionConfig, KeyConfig},
+    file::{self, StagedFile},
+};
+
+const PLACEHOLDER_START: &str = "{{";
+const PLACEHOLDER_END: &str = "}}";
+const PLACEHOLDER_PREFIX: &str = "secret.";
+
+#[derive(Deserialize, Default)]
+struct StoredSecrets {
+    #[serde(default)]
+    secrets: BTreeMap<String, String>,
+}
+
+#[derive(Serialize)]
+struct StoredSecretsRef<'a> {
+    secrets: &'a BTreeMap<String, String>,
+}
+
+/// Single secret values, stored encrypted with the default key in a vault file in the repo
+///
+/// plaintext files reference them as `{{ secret.NAME }}`, which is replaced with the value when
+/// the file is applied. The vault is only decrypted once a secret is actually needed
+pub struct Vault {
+    path: PathBuf,
+    encryption: EncryptionConfig,
+    secrets: OnceCell<BTreeMap<String, String>>,
+}
+
+impl Drop for Vault {
+    fn drop(&mut self) {
+        if let Some(secrets) = self.secrets.take() {
+            for value in secrets.into_values() {
+                drop(Zeroizing::new(value));
+            }
+        }
+    }
+}
+
+impl Vault {
+    pub fn open(path: &Path, encryption: &EncryptionConfig) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            encryption: encryption.clone(),
+            secrets: OnceCell::new(),
+        }
+    }
+
+    /// whether any secret was ever stored, without decrypting the vault
+    pub fn exists(&self) -> bool {
+        self.path.exists()
+    }
+
+    #[instrument(skip(self))]
+    fn secrets(&self) -> Result<&BTreeMap<String, String>> {
+        if let Some(secrets) = self.secrets.get() {
+            return Ok(secrets);
+        }
+
+        let secrets = if self.exists() {
+            let key = self.encryption.key(None)?;
+            let encrypted = std::fs::read(&self.path)?;
+            let contents = file::decrypt_contents(&encrypted, key)?;
+            let stored: StoredSecrets = toml::from_str(std::str::from_utf8(&contents)?)?;
+            tracing::trace!("decrypted vault");
+            stored.secrets
+        } else {
+            BTreeMap::new()
+        };
+
+        Ok(self.secrets.get_or_init(|| secrets))
+    }
+
+    fn secrets_mut(&mut self) -> Result<&mut BTreeMap<String, String>> {
+        self.secrets()?;
+        Ok(self.secrets.get_mut().unwrap())
+    }
+
+    pub fn get(&self, name: &str) -> Result<Option<&str>> {
+        Ok(self.secrets()?.get(name).map(String::as_str))
+    }
+
+    pub fn names(&self) -> Result<Vec<&str>> {
+        Ok(self.secrets()?.keys().map(String::as_str).collect())
+    }
+
+    pub fn set(&mut self, name: &str, value: String) -> Result<()> {
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(anyhow!(
+                "'{name}' is not a valid secret name, only letters, digits and '_' are allowed"
+            ));
+        }
+
+        if let Some(previous) = self.secrets_mut()?.insert(name.to_string(), value) {
+            drop(Zeroizing::new(previous));
+        }
+        Ok(())
+    }
+
+    /// remove a secret, returning whether it existed
+    pub fn remove(&mut self, name: &str) -> Result<bool> {
+        let removed = self.secrets_mut()?.remove(name);
+        let existed = removed.is_some();
+        drop(removed.map(Zeroizing::new));
+        Ok(existed)
+    }
+
+    /// encrypt the vault with the default key and write it to the repo
+    #[instrument(skip(self))]
+    pub fn write(&self) -> Result<()> {
+        self.stage(self.encryption.key(None)?)?.commit()?;
+
+        tracing::trace!("wrote vault");
+        Ok(())
+    }
+
+    /// encrypt the vault with `key` next to the vault file, which is only replaced once the
+    /// returned `StagedFile` is committed. Used by `rekey` to replace the vault along with the
+    /// files encrypted with the default key
+    #[instrument(skip(self, key))]
+    pub(crate) fn stage(&self, key: &KeyConfig) -> Result<StagedFile> {
+        let stored = StoredSecretsRef {
+            secrets: self.secrets()?,
+        };
+        let contents = Zeroizing::new(toml::to_string(&stored)?);
+        let encrypted = file::encrypt_armored(contents.as_bytes(), key)?;
+
+        file::stage_file(&self.path, None, |file| {
+            Ok(file.write_all(encrypted.as_bytes())?)
+        })
+    }
+
+    /// replace every `{{ secret.NAME }}` placeholder in `contents` with the value of the secret
+    pub fn render(&self, contents: &str) -> Result<Zeroizing<String>> {
+        let mut rendered = Zeroizing::new(String::with_capacity(contents.len()));
+        let mut rest = contents;
+
+        while let Some((start, name, end)) = next_placeholder(rest) {
+            let Some(value) = self.get(name)? else {
+                return Err(anyhow!("the secret '{name}' is not in the vault"));
+            };
+
+            rendered.push_str(&rest[..start]);
+            rendered.push_str(value);
+            rest = &rest[end..];
+        }
+
+        rendered.push_str(rest);
+        Ok(rendered)
+    }
+
+    /// replace the values of all secrets in `contents` with their placeholders, so that the
+    /// contents can be stored in plaintext
+    ///
+    /// the vault is not decrypted if no secret was ever stored
+    pub fn redact(&self, contents: &str) -> Result<String> {
+        if !self.exists() {
+            return Ok(contents.to_string());
+        }
+
+        // longer values first, so that a value containing another one is replaced as a whole
+        let mut secrets: Vec<_> = self
+            .secrets()?
+            .iter()
+            .filter(|(_, value)| !value.is_empty())
+            .collect();
+        secrets.sort_by_key(|(_, value)| std::cmp::Reverse(value.len()));
+
+        let mut redacted = contents.to_string();
+        for (name, value) in secrets.into_iter() {
+            redacted = redacted.replace(value.as_str(), &placeholder(name));
+        }
+
+        Ok(redacted)
+    }
+}
+
+/// whether `contents` reference any secret of the vault
+pub fn has_placeholders(contents: &str) -> bool {
+    next_placeholder(contents).is_some()
+}
+
+fn placeholder(name: &str) -> String {
+    format!("{PLACEHOLDER_START} {PLACEHOLDER_PREFIX}{name} {PLACEHOLDER_END}")
+}
+
+/// find the first `{{ secret.NAME }}` placeholder, returning its start, name and end
+fn next_placeholder(contents: &str) -> Option<(usize, &str, usize)> {
+    let mut offset = 0;
+
+    while let Some(start) = contents[offset..].find(PLACEHOLDER_START) {
+        let start = offset + start;
+        let inner_start = start + PLACEHOLDER_START.len();
+
+        let inner_end = inner_start + contents[inner_start..].find(PLACEHOLDER_END)?;
+
+        let name = contents[inner_start..inner_end]
+            .trim()
+            .strip_prefix(PLACEHOLDER_PREFIX)
+            .filter(|name| {
+                !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            });
+
+        if let Some(name) = name {
+            return Some((start, name, inner_end + PLACEHOLDER_END.len()));
+        }
+
+        offset = inner_start;
+    }
+
+    None
+}